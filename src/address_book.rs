@@ -0,0 +1,523 @@
+// Persistent book of the known peer addresses.
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::netgroup::{netgroup, LOCAL_GROUP};
+use crate::outbound::{backoff, MAX_RETRIES};
+
+/// Name of the address book file, in the data directory.
+pub const ADDRESS_BOOK_FILE: &str = "peers.txt";
+/// Number of buckets for the addresses we never connected to.
+pub const NEW_BUCKETS: usize = 256;
+/// Number of buckets for the addresses we successfully connected to.
+pub const TRIED_BUCKETS: usize = 64;
+/// Maximum number of addresses in a bucket.
+pub const BUCKET_SIZE: usize = 64;
+/// Number of new buckets the addresses given by a same source group can fill.
+pub const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 16;
+/// Number of tried buckets the addresses of a same group can fill.
+pub const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+/// What we know about a peer address.
+///
+/// Times are in seconds since the Unix epoch, 0 meaning never.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AddressInfo {
+    pub addr: SocketAddr,
+    pub last_seen: u64,  // Last time the node was known to be alive.
+    pub last_attempt: u64,
+    pub last_success: u64,
+    pub successes: u32,
+    pub failures: u32,  // Failed attempts since the last success.
+    pub source: Option<SocketAddr>,  // Peer which told us about this address.
+    pub tried: bool,  // In the tried table, or in the new one.
+}
+
+impl AddressInfo {
+    fn new(addr: SocketAddr, last_seen: u64, source: Option<SocketAddr>) -> Self {
+        AddressInfo {
+            addr,
+            last_seen,
+            last_attempt: 0,
+            last_success: 0,
+            successes: 0,
+            failures: 0,
+            source,
+            tried: false,
+        }
+    }
+
+    /// Whether the backoff since the last failed attempt is over.
+    fn is_due(&self, now: u64) -> bool {
+        self.failures == 0
+            || now >= self.last_attempt + backoff(self.failures).as_secs()
+    }
+
+    /// Line representation of the info, as saved in the file.
+    fn to_line(&self) -> String {
+        let source = match self.source {
+            Some(source) => source.to_string(),
+            None => "-".to_string(),
+        };
+
+        format!("{} {} {} {} {} {} {} {}",
+            self.addr, self.last_seen, self.last_attempt, self.last_success,
+            self.successes, self.failures, source, self.tried as u8)
+    }
+
+    /// Reads a line of the file.
+    /// The tried flag is optional, for the files saved without it.
+    fn from_line(line: &str) -> Result<Self, &'static str> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 7 && fields.len() != 8 {
+            return Err("Wrong number of fields");
+        }
+
+        let number = |field: &str| field.parse::<u64>().map_err(|_| "Invalid number");
+        let source = match fields[6] {
+            "-" => None,
+            source => Some(source.parse().map_err(|_| "Invalid source address")?),
+        };
+        let last_success = number(fields[3])?;
+        let tried = match fields.get(7) {
+            Some(tried) => number(tried)? != 0,
+            None => last_success != 0,
+        };
+
+        Ok(AddressInfo {
+            addr: fields[0].parse().map_err(|_| "Invalid address")?,
+            last_seen: number(fields[1])?,
+            last_attempt: number(fields[2])?,
+            last_success,
+            successes: number(fields[4])? as u32,
+            failures: number(fields[5])? as u32,
+            source,
+            tried,
+        })
+    }
+}
+
+/// Every peer address we learned about.
+///
+/// Addresses come from the whoami and addr messages and from the
+/// manual connections. The book is saved in the data directory
+/// and loaded back when the server starts.
+///
+/// Addresses are kept in two tables of fixed size buckets: the new table
+/// for the ones we never connected to, and the tried table for the others.
+/// A new address is placed according to its network group and to the
+/// group of the peer that told us about it, so that a single peer can
+/// only fill a few buckets, whatever the number of addresses it sends.
+/// Tried addresses are placed according to their own group only.
+#[derive(Debug)]
+pub struct AddressBook {
+    key: u64,  // Secret key, so that peers can not guess the buckets.
+    addresses: HashMap<SocketAddr, AddressInfo>,
+    new_buckets: Vec<Vec<SocketAddr>>,
+    tried_buckets: Vec<Vec<SocketAddr>>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        AddressBook::with_key(RandomState::new().hash_one(0u64))
+    }
+
+    /// Creates an empty book, with the given secret key.
+    pub fn with_key(key: u64) -> Self {
+        AddressBook {
+            key,
+            addresses: HashMap::new(),
+            new_buckets: vec![Vec::new(); NEW_BUCKETS],
+            tried_buckets: vec![Vec::new(); TRIED_BUCKETS],
+        }
+    }
+
+    /// Loads the book saved in the given file.
+    /// A missing file gives an empty book.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(AddressBook::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut lines = content.lines().peekable();
+        let key = lines.peek()
+            .and_then(|line| line.strip_prefix("key "))
+            .and_then(|key| key.trim().parse().ok());
+        let mut book = match key {
+            Some(key) => {
+                lines.next();
+                AddressBook::with_key(key)
+            },
+            None => AddressBook::new(),
+        };
+
+        for line in lines.filter(|l| !l.trim().is_empty()) {
+            match AddressInfo::from_line(line) {
+                Ok(info) => book.insert(info),
+                Err(err) => println!("Ignoring address book entry '{}': {}", line, err),
+            }
+        }
+
+        Ok(book)
+    }
+
+    /// Saves the book in the given file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Write in a temporary file first, so that a crash can not
+        // leave us with half a book.
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        writeln!(file, "key {}", self.key)?;
+        for info in self.addresses.values() {
+            writeln!(file, "{}", info.to_line())?;
+        }
+        file.sync_all()?;
+
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddressInfo> {
+        self.addresses.get(addr)
+    }
+
+    /// Adds an address, or updates its last seen time.
+    pub fn add(&mut self, addr: SocketAddr, last_seen: u64, source: Option<SocketAddr>) {
+        match self.addresses.get_mut(&addr) {
+            Some(info) => info.last_seen = info.last_seen.max(last_seen),
+            None => self.insert(AddressInfo::new(addr, last_seen, source)),
+        }
+    }
+
+    /// A connection to this address has been engaged.
+    pub fn attempt(&mut self, addr: &SocketAddr, now: u64) {
+        if let Some(info) = self.addresses.get_mut(addr) {
+            info.last_attempt = now;
+        }
+    }
+
+    /// The connection to this address went through the handshake.
+    /// The address moves to the tried table.
+    pub fn success(&mut self, addr: &SocketAddr, now: u64) {
+        let mut info = match self.addresses.get(addr) {
+            Some(info) => info.clone(),
+            None => return,
+        };
+
+        info.last_seen = now;
+        info.last_success = now;
+        info.successes += 1;
+        info.failures = 0;
+
+        if info.tried {
+            self.addresses.insert(*addr, info);
+        } else {
+            self.remove(addr);
+            info.tried = true;
+            self.insert(info);
+        }
+    }
+
+    /// The connection to this address failed.
+    /// The address is forgotten after too many failures.
+    pub fn failure(&mut self, addr: &SocketAddr) {
+        let forget = match self.addresses.get_mut(addr) {
+            Some(info) => {
+                info.failures += 1;
+                info.failures > MAX_RETRIES
+            },
+            None => false,
+        };
+
+        if forget {
+            println!("Giving up on {}", addr);
+            self.remove(addr);
+        }
+    }
+
+    /// Addresses to connect to, best ones first.
+    ///
+    /// Addresses still waiting for their backoff or refused by
+    /// `filter` are skipped. The most recently successful addresses
+    /// come first, then the ones that failed the least.
+    ///
+    /// At most one address is taken per network group, and none from
+    /// the `used_groups`. The local group is not restricted.
+    pub fn select<F>(&self, count: usize, now: u64, used_groups: &HashSet<Vec<u8>>, filter: F)
+        -> Vec<SocketAddr>
+        where F: Fn(&SocketAddr) -> bool {
+        let mut candidates: Vec<&AddressInfo> = self.addresses.values()
+            .filter(|info| info.is_due(now) && filter(&info.addr))
+            .collect();
+
+        candidates.sort_by(|a, b| {
+            b.last_success.cmp(&a.last_success)
+                .then(a.failures.cmp(&b.failures))
+                .then(b.last_seen.cmp(&a.last_seen))
+                .then(a.addr.cmp(&b.addr))
+        });
+
+        let mut groups = used_groups.clone();
+        candidates.into_iter()
+            .filter(|info| {
+                let group = netgroup(&info.addr.ip());
+                group == LOCAL_GROUP || groups.insert(group)
+            })
+            .take(count)
+            .map(|info| info.addr)
+            .collect()
+    }
+
+    /// Address for a feeler connection: one of the new table,
+    /// for which `filter` is true, attempted the longest time ago.
+    pub fn select_feeler<F>(&self, now: u64, filter: F) -> Option<SocketAddr>
+        where F: Fn(&SocketAddr) -> bool {
+        self.addresses.values()
+            .filter(|info| !info.tried && info.is_due(now) && filter(&info.addr))
+            .min_by_key(|info| (info.last_attempt, std::cmp::Reverse(info.last_seen), info.addr))
+            .map(|info| info.addr)
+    }
+
+    /// Places an address in its table.
+    fn insert(&mut self, info: AddressInfo) {
+        if info.tried {
+            self.insert_tried(info);
+        } else {
+            self.insert_new(info);
+        }
+    }
+
+    /// Places an address in its new bucket.
+    /// If the bucket is full, its worst address is forgotten.
+    fn insert_new(&mut self, info: AddressInfo) {
+        let bucket = self.new_bucket(&info.addr, info.source);
+        if self.new_buckets[bucket].len() >= BUCKET_SIZE {
+            let worst = self.new_buckets[bucket].iter()
+                .filter_map(|addr| self.addresses.get(addr))
+                .max_by_key(|i| (i.failures, std::cmp::Reverse(i.last_seen)))
+                .map(|i| i.addr);
+            if let Some(worst) = worst {
+                self.remove(&worst);
+            }
+        }
+
+        self.new_buckets[bucket].push(info.addr);
+        self.addresses.insert(info.addr, info);
+    }
+
+    /// Places an address in its tried bucket.
+    /// If the bucket is full, its oldest address goes back to the new table.
+    fn insert_tried(&mut self, info: AddressInfo) {
+        let bucket = self.tried_bucket(&info.addr);
+        if self.tried_buckets[bucket].len() >= BUCKET_SIZE {
+            let oldest = self.tried_buckets[bucket].iter()
+                .filter_map(|addr| self.addresses.get(addr))
+                .min_by_key(|i| i.last_success)
+                .cloned();
+            if let Some(mut oldest) = oldest {
+                self.remove(&oldest.addr);
+                oldest.tried = false;
+                self.insert_new(oldest);
+            }
+        }
+
+        self.tried_buckets[bucket].push(info.addr);
+        self.addresses.insert(info.addr, info);
+    }
+
+    /// Forgets an address.
+    fn remove(&mut self, addr: &SocketAddr) {
+        let info = match self.addresses.remove(addr) {
+            Some(info) => info,
+            None => return,
+        };
+
+        let bucket = if info.tried {
+            let index = self.tried_bucket(addr);
+            &mut self.tried_buckets[index]
+        } else {
+            let index = self.new_bucket(addr, info.source);
+            &mut self.new_buckets[index]
+        };
+        bucket.retain(|a| a != addr);
+    }
+
+    /// New bucket of an address, given by the peer `source`.
+    fn new_bucket(&self, addr: &SocketAddr, source: Option<SocketAddr>) -> usize {
+        let group = netgroup(&addr.ip());
+        let source_group = netgroup(&source.unwrap_or(*addr).ip());
+
+        let slot = self.hash(&(&group, &source_group)) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        (self.hash(&(&source_group, slot)) % NEW_BUCKETS as u64) as usize
+    }
+
+    /// Tried bucket of an address.
+    fn tried_bucket(&self, addr: &SocketAddr) -> usize {
+        let slot = self.hash(addr) % TRIED_BUCKETS_PER_GROUP;
+        (self.hash(&(netgroup(&addr.ip()), slot)) % TRIED_BUCKETS as u64) as usize
+    }
+
+    fn hash<T: Hash>(&self, value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.key.hash(&mut hasher);
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new("10.0.0.1".parse().unwrap(), port)
+    }
+
+    fn public_addr(a: u8, b: u8, c: u8) -> SocketAddr {
+        SocketAddr::new(format!("{}.{}.{}.1", a, b, c).parse().unwrap(), 8000)
+    }
+
+    #[test]
+    fn test_select_prefers_successful() {
+        let mut book = AddressBook::new();
+        let none = HashSet::new();
+        book.add(addr(1), 100, None);
+        book.add(addr(2), 200, Some(addr(1)));
+        book.add(addr(3), 50, None);
+        assert_eq!(book.select(3, 1000, &none, |_| true), vec![addr(2), addr(1), addr(3)]);
+
+        book.attempt(&addr(3), 1000);
+        book.success(&addr(3), 1000);
+        assert!(book.get(&addr(3)).unwrap().tried);
+        assert_eq!(book.select(1, 1000, &none, |_| true), vec![addr(3)]);
+        assert_eq!(book.select(1, 1000, &none, |a| *a != addr(3)), vec![addr(2)]);
+    }
+
+    #[test]
+    fn test_select_one_per_group() {
+        let mut book = AddressBook::new();
+        book.add(public_addr(1, 2, 3), 300, None);
+        book.add(public_addr(1, 2, 4), 200, None);
+        book.add(public_addr(1, 3, 3), 100, None);
+        book.add(public_addr(5, 6, 7), 50, None);
+
+        let selected = book.select(4, 1000, &HashSet::new(), |_| true);
+        assert_eq!(selected, vec![public_addr(1, 2, 3), public_addr(1, 3, 3), public_addr(5, 6, 7)]);
+
+        let used: HashSet<Vec<u8>> = vec![netgroup(&public_addr(1, 2, 0).ip())].into_iter().collect();
+        let selected = book.select(4, 1000, &used, |_| true);
+        assert_eq!(selected, vec![public_addr(1, 3, 3), public_addr(5, 6, 7)]);
+    }
+
+    #[test]
+    fn test_select_feeler() {
+        let mut book = AddressBook::new();
+        assert_eq!(book.select_feeler(1000, |_| true), None);
+
+        book.add(addr(1), 100, None);
+        book.add(addr(2), 200, None);
+        book.success(&addr(2), 300);
+        assert_eq!(book.select_feeler(1000, |_| true), Some(addr(1)));
+        assert_eq!(book.select_feeler(1000, |a| *a != addr(1)), None);
+    }
+
+    #[test]
+    fn test_flooding_source() {
+        let mut book = AddressBook::new();
+        let source = public_addr(66, 66, 66);
+        for a in 1..=200u8 {
+            for b in 0..50u8 {
+                book.add(public_addr(a, b, 0), 100, Some(source));
+            }
+        }
+        assert!(book.len() <= NEW_BUCKETS_PER_SOURCE_GROUP as usize * BUCKET_SIZE);
+
+        // Addresses from another source still find room.
+        let honest = public_addr(77, 77, 77);
+        book.add(public_addr(8, 8, 8), 100, Some(honest));
+        assert!(book.get(&public_addr(8, 8, 8)).is_some());
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut book = AddressBook::new();
+        let none = HashSet::new();
+        book.add(addr(1), 100, None);
+
+        book.attempt(&addr(1), 1000);
+        book.failure(&addr(1));
+        assert!(book.select(1, 1000, &none, |_| true).is_empty());
+        assert_eq!(book.select(1, 1000 + backoff(1).as_secs(), &none, |_| true), vec![addr(1)]);
+
+        for _ in 0..MAX_RETRIES {
+            book.failure(&addr(1));
+        }
+        assert!(book.is_empty());
+        assert!(book.new_buckets.iter().all(|b| b.is_empty()));
+    }
+
+    #[test]
+    fn test_line_conversion() {
+        let mut info = AddressInfo::new(addr(1), 42, Some(addr(2)));
+        info.failures = 3;
+        assert_eq!(AddressInfo::from_line(&info.to_line()), Ok(info));
+
+        let mut info = AddressInfo::new("[::1]:8000".parse().unwrap(), 42, None);
+        info.tried = true;
+        assert_eq!(AddressInfo::from_line(&info.to_line()), Ok(info));
+
+        // Saved without the tried flag.
+        let info = AddressInfo::from_line("10.0.0.1:1 42 40 40 1 0 -").unwrap();
+        assert!(info.tried);
+
+        assert!(AddressInfo::from_line("10.0.0.1:1 0 0").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = env::temp_dir()
+            .join(format!("rustycoin-book-{}", std::process::id()))
+            .join(ADDRESS_BOOK_FILE);
+
+        let mut book = AddressBook::new();
+        book.add(addr(1), 100, None);
+        book.add(addr(2), 200, Some(addr(1)));
+        book.success(&addr(2), 300);
+        book.save(&path).unwrap();
+
+        let loaded = AddressBook::load(&path).unwrap();
+        assert_eq!(loaded.key, book.key);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&addr(2)), book.get(&addr(2)));
+        assert_eq!(loaded.tried_buckets, book.tried_buckets);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(AddressBook::load(&path).unwrap().is_empty());
+    }
+}
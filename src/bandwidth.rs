@@ -0,0 +1,149 @@
+// Limits on the bytes sent and received.
+use std::time::{Duration, Instant};
+
+/// Length of the period the upload cap applies to.
+pub const UPLOAD_CAP_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Allows `rate` bytes per second, with bursts of up to one second.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full.
+    pub fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Bytes which can be transferred right now.
+    pub fn available(&mut self, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64)
+            .min(self.rate as f64);
+        self.last_refill = now;
+        self.tokens as usize
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens = (self.tokens - bytes as f64).max(0.0);
+    }
+}
+
+/// Upload and download limits, each one optional.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+    daily_cap: Option<u64>,
+    uploaded: u64,  // Bytes sent since the start of the period.
+    period_start: Option<Instant>,
+}
+
+impl RateLimit {
+    /// Rates are in bytes per second.
+    pub fn new(upload: Option<u64>, download: Option<u64>, now: Instant) -> Self {
+        RateLimit {
+            upload: upload.map(|rate| TokenBucket::new(rate, now)),
+            download: download.map(|rate| TokenBucket::new(rate, now)),
+            daily_cap: None,
+            uploaded: 0,
+            period_start: Some(now),
+        }
+    }
+
+    /// Bytes which can be sent in a day before `cap_reached`.
+    pub fn set_daily_cap(&mut self, cap: Option<u64>) {
+        self.daily_cap = cap;
+    }
+
+    pub fn upload_allowance(&mut self, now: Instant) -> usize {
+        match &mut self.upload {
+            Some(bucket) => bucket.available(now),
+            None => usize::MAX,
+        }
+    }
+
+    pub fn download_allowance(&mut self, now: Instant) -> usize {
+        match &mut self.download {
+            Some(bucket) => bucket.available(now),
+            None => usize::MAX,
+        }
+    }
+
+    pub fn uploaded(&mut self, bytes: usize, now: Instant) {
+        if let Some(bucket) = &mut self.upload {
+            bucket.consume(bytes);
+        }
+        self.new_period(now);
+        self.uploaded += bytes as u64;
+    }
+
+    pub fn downloaded(&mut self, bytes: usize) {
+        if let Some(bucket) = &mut self.download {
+            bucket.consume(bytes);
+        }
+    }
+
+    /// Whether the bytes sent today passed the daily cap.
+    pub fn cap_reached(&mut self, now: Instant) -> bool {
+        self.new_period(now);
+        match self.daily_cap {
+            Some(cap) => self.uploaded >= cap,
+            None => false,
+        }
+    }
+
+    /// Bytes sent since the start of the period.
+    pub fn uploaded_today(&self) -> u64 {
+        self.uploaded
+    }
+
+    fn new_period(&mut self, now: Instant) {
+        let start = *self.period_start.get_or_insert(now);
+        if now.saturating_duration_since(start) >= UPLOAD_CAP_PERIOD {
+            self.period_start = Some(now);
+            self.uploaded = 0;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1000, now);
+        assert_eq!(bucket.available(now), 1000);
+        bucket.consume(800);
+        assert_eq!(bucket.available(now), 200);
+        assert_eq!(bucket.available(now + Duration::from_millis(500)), 700);
+        // Bursts are limited to one second.
+        assert_eq!(bucket.available(now + Duration::from_secs(10)), 1000);
+    }
+
+    #[test]
+    fn test_daily_cap() {
+        let now = Instant::now();
+        let mut limit = RateLimit::new(None, Some(100), now);
+        assert_eq!(limit.upload_allowance(now), usize::MAX);
+        assert_eq!(limit.download_allowance(now), 100);
+
+        limit.set_daily_cap(Some(1000));
+        limit.uploaded(600, now);
+        assert!(!limit.cap_reached(now));
+        limit.uploaded(600, now + Duration::from_secs(60));
+        assert!(limit.cap_reached(now + Duration::from_secs(60)));
+        assert!(!limit.cap_reached(now + UPLOAD_CAP_PERIOD));
+        assert_eq!(limit.uploaded_today(), 0);
+    }
+}
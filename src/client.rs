@@ -1,14 +1,24 @@
-mod server;
-mod node;
-mod messages;
-
-use server::Server;
+use rustycoin::config::{EncryptionPolicy, ServerConfig};
+use rustycoin::messages::states::{DEFAULT_LAN_BROADCAST, LAN_SECRET_VAR};
+use rustycoin::server::Server;
 
 fn main() {
-    let mut client = Server::new("127.0.0.1:9000").unwrap();
-    let addr = "127.0.0.1:8000".parse().unwrap();
-
-    client.connect(addr).unwrap();
+    let secret = std::env::var(LAN_SECRET_VAR).ok();
+    let listen = if secret.is_some() { "0.0.0.0:9000" } else { "127.0.0.1:9000" };
+    let mut config = ServerConfig::builder()
+        .listen(listen)
+        .data_dir(".rustycoin-client")
+        .handle_signals(true)
+        .encryption(EncryptionPolicy::Preferred);
+    if let Some(secret) = &secret {
+        // The server listens for the beacons, we learn about it from its answer.
+        config = config.lan_discovery("0.0.0.0:0", DEFAULT_LAN_BROADCAST, secret);
+    }
+    let config = config.build().unwrap();
 
+    let mut client = Server::new(config).unwrap();
+    if secret.is_none() {
+        client.add_peer("127.0.0.1:8000".parse().unwrap());
+    }
     client.launch().unwrap();
 }
@@ -0,0 +1,572 @@
+// Configuration of the server.
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::messages::states::*;
+use crate::lan::MAX_SECRET_SIZE;
+use crate::netgroup::Cidr;
+
+/// Policy applied to the nodes accepted by a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenPolicy {
+    pub relay: bool,  // Addresses and transactions are relayed to and from these nodes.
+    pub whitelisted: bool,  // These nodes are exempt from the per-IP limit and from eviction.
+}
+
+impl Default for ListenPolicy {
+    fn default() -> Self {
+        ListenPolicy {
+            relay: true,
+            whitelisted: false,
+        }
+    }
+}
+
+/// A socket the server listens on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenConfig {
+    pub addr: SocketAddr,
+    pub policy: ListenPolicy,
+}
+
+/// Whether the connections are encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    Disabled,
+    Preferred,  // Falls back to plaintext with the nodes not supporting it.
+    Required,
+}
+
+/// Outbound connections sent through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyScope {
+    All,
+    Routable,  // Local and private addresses are dialed directly.
+}
+
+/// SOCKS5 proxy used for outbound connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    pub addr: SocketAddr,
+    pub scope: ProxyScope,
+}
+
+/// Discovery of the nodes of the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanConfig {
+    pub bind: SocketAddr,  // Where the beacons are received.
+    pub broadcast: SocketAddr,  // Where our beacons are sent.
+    pub secret: Vec<u8>,  // Shared by the nodes, signs the beacons.
+}
+
+/// Settings of a `Server`.
+///
+/// Use `ServerConfig::builder()` to create one, every
+/// setting not given to the builder keeps its default value.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub listeners: Vec<ListenConfig>,
+    pub magic: u32,
+    pub version: u32,
+    pub services: Vec<String>,
+    pub ping_interval: Duration,  // A ping is sent to each node at this interval.
+    pub waiting_time: Duration,  // Maximum time spent waiting for events.
+    pub last_seen_threshold: Duration,  // A silent node is considered dead after this.
+    pub handshake_timeout: Duration,  // A node not done with the handshake is dropped after this.
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    pub max_per_ip: usize,  // Inbound connections accepted from a same IP.
+    pub target_outbound: usize,  // Number of full-relay outbound connections to keep.
+    pub block_relay_only: usize,  // Number of block-relay-only outbound connections to keep.
+    pub feeler_interval: Duration,  // A feeler connection is made at this interval, 0 to disable.
+    pub data_dir: PathBuf,
+    pub handle_signals: bool,  // Shut down on SIGINT/SIGTERM.
+    pub proxy: Option<ProxyConfig>,
+    pub encryption: EncryptionPolicy,
+    pub worker_threads: usize,  // Threads processing the messages, 0 to do it on the network thread.
+    pub dandelion: bool,  // Our transactions go along a stem path before being broadcast.
+    pub peer_upload_rate: Option<u64>,  // Bytes per second sent to each node.
+    pub peer_download_rate: Option<u64>,  // Bytes per second read from each node.
+    pub upload_rate: Option<u64>,  // Bytes per second sent to all the nodes.
+    pub download_rate: Option<u64>,  // Bytes per second read from all the nodes.
+    pub daily_upload_cap: Option<u64>,  // Past it, historical blocks are not served to inbound nodes.
+    pub lan_discovery: Option<LanConfig>,
+    pub whitelist: Vec<Cidr>,  // Exempt from the limits, always relay to us.
+    pub blacklist: Vec<Cidr>,  // Never accepted nor dialed.
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::new()
+    }
+}
+
+/// Builder of a `ServerConfig`.
+#[derive(Debug)]
+pub struct ServerConfigBuilder {
+    listeners: Option<Vec<(String, ListenPolicy)>>,
+    magic: u32,
+    version: u32,
+    services: Vec<String>,
+    ping_interval: Duration,
+    waiting_time: Duration,
+    last_seen_threshold: Duration,
+    handshake_timeout: Duration,
+    max_inbound: usize,
+    max_outbound: usize,
+    max_per_ip: usize,
+    target_outbound: usize,
+    block_relay_only: usize,
+    feeler_interval: Duration,
+    data_dir: PathBuf,
+    handle_signals: bool,
+    proxy: Option<(String, ProxyScope)>,
+    encryption: EncryptionPolicy,
+    worker_threads: usize,
+    dandelion: bool,
+    peer_upload_rate: Option<u64>,
+    peer_download_rate: Option<u64>,
+    upload_rate: Option<u64>,
+    download_rate: Option<u64>,
+    daily_upload_cap: Option<u64>,
+    lan_discovery: Option<(String, String, Vec<u8>)>,
+    whitelist: Vec<String>,
+    blacklist: Vec<String>,
+}
+
+impl ServerConfigBuilder {
+    /// Creates a builder filled with the default settings.
+    pub fn new() -> Self {
+        ServerConfigBuilder {
+            listeners: None,
+            magic: MAGIC,
+            version: VERSION,
+            services: SERVICES.iter().map(|s| s.to_string()).collect(),
+            ping_interval: Duration::from_secs(PING_CALLBACK.into()),
+            waiting_time: Duration::from_secs(WAITING_TIME.into()),
+            last_seen_threshold: Duration::from_secs(LAST_SEEN_THRESHOLD.into()),
+            handshake_timeout: Duration::from_secs(HANDSHAKE_TIMEOUT.into()),
+            max_inbound: MAX_INBOUND,
+            max_outbound: MAX_OUTBOUND,
+            max_per_ip: MAX_PER_IP,
+            target_outbound: TARGET_OUTBOUND,
+            block_relay_only: BLOCK_RELAY_ONLY,
+            feeler_interval: Duration::from_secs(FEELER_INTERVAL.into()),
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            handle_signals: false,
+            proxy: None,
+            encryption: EncryptionPolicy::Disabled,
+            worker_threads: WORKER_THREADS,
+            dandelion: false,
+            peer_upload_rate: None,
+            peer_download_rate: None,
+            upload_rate: None,
+            download_rate: None,
+            daily_upload_cap: None,
+            lan_discovery: None,
+            whitelist: Vec::new(),
+            blacklist: Vec::new(),
+        }
+    }
+
+    /// Address the server is listening on, such as "127.0.0.1:8000".
+    /// Replaces the listeners given before.
+    pub fn listen(mut self, addr: &str) -> Self {
+        self.listeners = Some(vec![(addr.to_string(), ListenPolicy::default())]);
+        self
+    }
+
+    /// Adds an address to listen on, such as "[::]:8000" or "192.168.1.2:8000",
+    /// with the policy applied to the nodes accepted on it.
+    pub fn add_listener(mut self, addr: &str, policy: ListenPolicy) -> Self {
+        self.listeners.get_or_insert_with(Vec::new)
+            .push((addr.to_string(), policy));
+        self
+    }
+
+    pub fn magic(mut self, magic: u32) -> Self {
+        self.magic = magic;
+        self
+    }
+
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn services(mut self, services: Vec<String>) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    pub fn waiting_time(mut self, time: Duration) -> Self {
+        self.waiting_time = time;
+        self
+    }
+
+    pub fn last_seen_threshold(mut self, threshold: Duration) -> Self {
+        self.last_seen_threshold = threshold;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn max_inbound(mut self, max: usize) -> Self {
+        self.max_inbound = max;
+        self
+    }
+
+    pub fn max_outbound(mut self, max: usize) -> Self {
+        self.max_outbound = max;
+        self
+    }
+
+    pub fn max_per_ip(mut self, max: usize) -> Self {
+        self.max_per_ip = max;
+        self
+    }
+
+    pub fn target_outbound(mut self, target: usize) -> Self {
+        self.target_outbound = target;
+        self
+    }
+
+    pub fn block_relay_only(mut self, count: usize) -> Self {
+        self.block_relay_only = count;
+        self
+    }
+
+    /// Interval between two feeler connections, 0 to disable them.
+    pub fn feeler_interval(mut self, interval: Duration) -> Self {
+        self.feeler_interval = interval;
+        self
+    }
+
+    /// Directory where the server keeps its files.
+    pub fn data_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.data_dir = dir.into();
+        self
+    }
+
+    /// Whether the server shuts down on SIGINT/SIGTERM.
+    /// Disabled by default, as it replaces the default behaviour
+    /// of these signals for the whole process.
+    pub fn handle_signals(mut self, handle: bool) -> Self {
+        self.handle_signals = handle;
+        self
+    }
+
+    /// SOCKS5 proxy, such as "127.0.0.1:9050", the outbound
+    /// connections in the scope are made through.
+    pub fn proxy(mut self, addr: &str, scope: ProxyScope) -> Self {
+        self.proxy = Some((addr.to_string(), scope));
+        self
+    }
+
+    /// Encryption of the connections, disabled by default.
+    /// The node's key is kept in the data directory.
+    pub fn encryption(mut self, policy: EncryptionPolicy) -> Self {
+        self.encryption = policy;
+        self
+    }
+
+    /// Threads parsing and validating the messages. With 0, everything
+    /// is done on the network thread, which keeps a run deterministic.
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.worker_threads = threads;
+        self
+    }
+
+    /// Whether our transactions are first passed along a random path of nodes,
+    /// then broadcast, which hides where they come from. Disabled by default.
+    pub fn dandelion(mut self, enabled: bool) -> Self {
+        self.dandelion = enabled;
+        self
+    }
+
+    /// Bytes per second sent to each node. Unlimited by default.
+    pub fn peer_upload_rate(mut self, rate: u64) -> Self {
+        self.peer_upload_rate = Some(rate);
+        self
+    }
+
+    /// Bytes per second read from each node. Unlimited by default.
+    pub fn peer_download_rate(mut self, rate: u64) -> Self {
+        self.peer_download_rate = Some(rate);
+        self
+    }
+
+    /// Bytes per second sent to all the nodes together. Unlimited by default.
+    pub fn upload_rate(mut self, rate: u64) -> Self {
+        self.upload_rate = Some(rate);
+        self
+    }
+
+    /// Bytes per second read from all the nodes together. Unlimited by default.
+    pub fn download_rate(mut self, rate: u64) -> Self {
+        self.download_rate = Some(rate);
+        self
+    }
+
+    /// Bytes sent in a day after which the inbound nodes are not
+    /// served historical blocks anymore. Unlimited by default.
+    pub fn daily_upload_cap(mut self, bytes: u64) -> Self {
+        self.daily_upload_cap = Some(bytes);
+        self
+    }
+
+    /// Finds the nodes of the local network: beacons are received on
+    /// `bind`, such as "0.0.0.0:8334", and ours are sent to `broadcast`,
+    /// such as "255.255.255.255:8334". Only the beacons signed with
+    /// the same secret are accepted, `DEFAULT_LAN_SECRET` is refused.
+    /// Disabled by default.
+    pub fn lan_discovery(mut self, bind: &str, broadcast: &str, secret: &str) -> Self {
+        self.lan_discovery = Some((bind.to_string(), broadcast.to_string(), secret.as_bytes().to_vec()));
+        self
+    }
+
+    /// Adds a range of addresses, such as "10.0.0.0/8", whose nodes are
+    /// exempt from the connection and rate limits, never evicted,
+    /// and always relay to us.
+    pub fn whitelist(mut self, range: &str) -> Self {
+        self.whitelist.push(range.to_string());
+        self
+    }
+
+    /// Adds a range of addresses, such as "203.0.113.0/24",
+    /// whose nodes are never accepted nor dialed.
+    pub fn blacklist(mut self, range: &str) -> Self {
+        self.blacklist.push(range.to_string());
+        self
+    }
+
+    /// Checks the settings and creates the config.
+    pub fn build(self) -> Result<ServerConfig, &'static str> {
+        let listeners = self.listeners.unwrap_or_else(|| {
+            vec![(DEFAULT_LISTEN_ADDR.to_string(), ListenPolicy::default())]
+        });
+        let mut listen_configs: Vec<ListenConfig> = Vec::new();
+        for (addr, policy) in listeners {
+            let addr = match addr.parse() {
+                Ok(addr) => addr,
+                Err(_) => return Err("Invalid listen address"),
+            };
+            if listen_configs.iter().any(|l| l.addr == addr) {
+                return Err("The same address is listened on twice");
+            }
+            listen_configs.push(ListenConfig { addr, policy });
+        }
+
+        if listen_configs.is_empty() {
+            return Err("The server needs at least one listen address");
+        }
+
+        if self.ping_interval == Duration::from_secs(0) {
+            return Err("The ping interval can not be null");
+        }
+
+        if self.waiting_time == Duration::from_secs(0) {
+            return Err("The waiting time can not be null");
+        }
+
+        if self.last_seen_threshold <= self.ping_interval {
+            return Err("The last seen threshold has to be greater than the ping interval");
+        }
+
+        let proxy = match self.proxy {
+            Some((addr, scope)) => match addr.parse() {
+                Ok(addr) => Some(ProxyConfig { addr, scope }),
+                Err(_) => return Err("Invalid proxy address"),
+            },
+            None => None,
+        };
+
+        if self.target_outbound + self.block_relay_only > self.max_outbound {
+            return Err("The outbound targets can not be greater than the outbound limit");
+        }
+
+        if self.services.iter().any(|s| !s.is_ascii()) {
+            return Err("Services can only contains ascii characters !");
+        }
+
+        let rates = [self.peer_upload_rate, self.peer_download_rate, self.upload_rate, self.download_rate];
+        if rates.contains(&Some(0)) {
+            return Err("The rate limits can not be null");
+        }
+
+        let lan_discovery = match self.lan_discovery {
+            Some((bind, broadcast, secret)) => match (bind.parse(), broadcast.parse()) {
+                _ if secret.is_empty() || secret.len() > MAX_SECRET_SIZE => {
+                    return Err("The LAN discovery secret has to be 1 to 64 bytes long");
+                },
+                _ if secret == DEFAULT_LAN_SECRET.as_bytes() => {
+                    return Err("The LAN discovery secret can not be the default one");
+                },
+                (Ok(bind), Ok(broadcast)) => Some(LanConfig { bind, broadcast, secret }),
+                _ => return Err("Invalid LAN discovery address"),
+            },
+            None => None,
+        };
+
+        let whitelist = match self.whitelist.iter().map(|range| range.parse()).collect() {
+            Ok(whitelist) => whitelist,
+            Err(_) => return Err("Invalid whitelisted range"),
+        };
+        let blacklist = match self.blacklist.iter().map(|range| range.parse()).collect() {
+            Ok(blacklist) => blacklist,
+            Err(_) => return Err("Invalid blacklisted range"),
+        };
+
+        Ok(ServerConfig {
+            listeners: listen_configs,
+            magic: self.magic,
+            version: self.version,
+            services: self.services,
+            ping_interval: self.ping_interval,
+            waiting_time: self.waiting_time,
+            last_seen_threshold: self.last_seen_threshold,
+            handshake_timeout: self.handshake_timeout,
+            max_inbound: self.max_inbound,
+            max_outbound: self.max_outbound,
+            max_per_ip: self.max_per_ip,
+            target_outbound: self.target_outbound,
+            block_relay_only: self.block_relay_only,
+            feeler_interval: self.feeler_interval,
+            data_dir: self.data_dir,
+            handle_signals: self.handle_signals,
+            proxy,
+            encryption: self.encryption,
+            worker_threads: self.worker_threads,
+            dandelion: self.dandelion,
+            peer_upload_rate: self.peer_upload_rate,
+            peer_download_rate: self.peer_download_rate,
+            upload_rate: self.upload_rate,
+            download_rate: self.download_rate,
+            daily_upload_cap: self.daily_upload_cap,
+            lan_discovery,
+            whitelist,
+            blacklist,
+        })
+    }
+}
+
+impl Default for ServerConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config() {
+        let config = ServerConfig::builder().build().unwrap();
+        assert_eq!(config.listeners, vec![ListenConfig {
+            addr: DEFAULT_LISTEN_ADDR.parse().unwrap(),
+            policy: ListenPolicy::default(),
+        }]);
+        assert_eq!(config.magic, MAGIC);
+        assert_eq!(config.services, vec!["node".to_string()]);
+        assert_eq!(config.ping_interval, Duration::from_secs(PING_CALLBACK.into()));
+        assert_eq!(config.worker_threads, WORKER_THREADS);
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = ServerConfig::builder()
+            .listen("127.0.0.1:9000")
+            .magic(42)
+            .ping_interval(Duration::from_millis(100))
+            .max_inbound(2)
+            .data_dir("/tmp/node")
+            .build()
+            .unwrap();
+        assert_eq!(config.listeners[0].addr, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.magic, 42);
+        assert_eq!(config.ping_interval, Duration::from_millis(100));
+        assert_eq!(config.max_inbound, 2);
+        assert_eq!(config.data_dir, PathBuf::from("/tmp/node"));
+        assert_eq!(config.proxy, None);
+        assert!(!config.dandelion);
+        assert_eq!(config.upload_rate, None);
+        assert!(config.whitelist.is_empty());
+
+        let config = ServerConfig::builder()
+            .proxy("127.0.0.1:9050", ProxyScope::Routable)
+            .build()
+            .unwrap();
+        assert_eq!(config.proxy, Some(ProxyConfig {
+            addr: "127.0.0.1:9050".parse().unwrap(),
+            scope: ProxyScope::Routable,
+        }));
+    }
+
+    #[test]
+    fn test_listeners() {
+        let whitelisted = ListenPolicy { relay: false, whitelisted: true };
+        let config = ServerConfig::builder()
+            .listen("0.0.0.0:8000")
+            .add_listener("[::1]:8001", whitelisted)
+            .build()
+            .unwrap();
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[1].addr, "[::1]:8001".parse().unwrap());
+        assert_eq!(config.listeners[1].policy, whitelisted);
+
+        let config = ServerConfig::builder()
+            .add_listener("[::]:8000", ListenPolicy::default())
+            .build()
+            .unwrap();
+        assert_eq!(config.listeners.len(), 1);
+
+        assert!(ServerConfig::builder()
+            .listen("127.0.0.1:8000")
+            .add_listener("127.0.0.1:8000", whitelisted)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(ServerConfig::builder().listen("localhost").build().is_err());
+        assert!(ServerConfig::builder().proxy("localhost:9050", ProxyScope::All).build().is_err());
+        assert!(ServerConfig::builder().waiting_time(Duration::from_secs(0)).build().is_err());
+        assert!(ServerConfig::builder()
+            .ping_interval(Duration::from_secs(10))
+            .last_seen_threshold(Duration::from_secs(5))
+            .build()
+            .is_err());
+        assert!(ServerConfig::builder()
+            .max_outbound(2)
+            .target_outbound(3)
+            .build()
+            .is_err());
+        assert!(ServerConfig::builder()
+            .max_outbound(4)
+            .target_outbound(3)
+            .block_relay_only(2)
+            .build()
+            .is_err());
+        assert!(ServerConfig::builder().upload_rate(0).build().is_err());
+        assert!(ServerConfig::builder().whitelist("10.0.0.0/40").build().is_err());
+        assert!(ServerConfig::builder().blacklist("localhost").build().is_err());
+        assert!(ServerConfig::builder().lan_discovery("0.0.0.0:8334", "broadcast", "secret").build().is_err());
+        assert!(ServerConfig::builder().lan_discovery("0.0.0.0:8334", "255.255.255.255:8334", "").build().is_err());
+        assert!(ServerConfig::builder()
+            .lan_discovery("0.0.0.0:8334", "255.255.255.255:8334", DEFAULT_LAN_SECRET)
+            .build()
+            .is_err());
+    }
+}
@@ -0,0 +1,98 @@
+// Control of a running server from other threads.
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use mio::Waker;
+
+use crate::messages::inv::Inventory;
+use crate::netgroup::Cidr;
+
+/// Requests other threads can send to a running server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Connect(SocketAddr),  // Adds a manual peer.
+    Disconnect(SocketAddr),  // Closes the connections with this peer.
+    Broadcast(String, Vec<u8>),  // Message type and payload, sent to every established node.
+    Relay(Inventory),  // A new block or transaction, announced to the nodes.
+    Reject(Inventory),  // An invalid block or transaction, never relayed.
+    Whitelist(Cidr),
+    Unwhitelist(Cidr),
+    Blacklist(Cidr),  // Also closes the connections with the nodes in the range.
+    Unblacklist(Cidr),
+    Shutdown,
+}
+
+/// Thread-safe handle on a server.
+///
+/// Commands are queued, then the server is woken up to process them.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    sender: Sender<Command>,
+    waker: Arc<Waker>,
+}
+
+impl ServerHandle {
+    pub fn new(sender: Sender<Command>, waker: Arc<Waker>) -> Self {
+        ServerHandle {
+            sender,
+            waker,
+        }
+    }
+
+    /// Sends a command to the server.
+    pub fn send(&self, command: Command) -> io::Result<()> {
+        self.sender.send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The server is stopped"))?;
+        self.waker.wake()
+    }
+
+    /// Asks the server to connect to a node, and to stay connected to it.
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.send(Command::Connect(addr))
+    }
+
+    /// Asks the server to close its connections with a node.
+    pub fn disconnect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.send(Command::Disconnect(addr))
+    }
+
+    /// Asks the server to send a message to every established node.
+    pub fn broadcast(&self, msg_type: &str, payload: Vec<u8>) -> io::Result<()> {
+        self.send(Command::Broadcast(msg_type.to_string(), payload))
+    }
+
+    /// Asks the server to announce a new block or transaction.
+    pub fn relay(&self, item: Inventory) -> io::Result<()> {
+        self.send(Command::Relay(item))
+    }
+
+    /// Tells the server a block or transaction is invalid.
+    pub fn reject(&self, item: Inventory) -> io::Result<()> {
+        self.send(Command::Reject(item))
+    }
+
+    /// Exempts the nodes of a range from the limits, the connected ones included.
+    pub fn whitelist(&self, range: Cidr) -> io::Result<()> {
+        self.send(Command::Whitelist(range))
+    }
+
+    pub fn unwhitelist(&self, range: Cidr) -> io::Result<()> {
+        self.send(Command::Unwhitelist(range))
+    }
+
+    /// Refuses the nodes of a range, and disconnects them.
+    pub fn blacklist(&self, range: Cidr) -> io::Result<()> {
+        self.send(Command::Blacklist(range))
+    }
+
+    pub fn unblacklist(&self, range: Cidr) -> io::Result<()> {
+        self.send(Command::Unblacklist(range))
+    }
+
+    /// Asks the server to stop.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.send(Command::Shutdown)
+    }
+}
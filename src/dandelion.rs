@@ -0,0 +1,88 @@
+// Dandelion: transactions go along a single path before being broadcast.
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::messages::inv::Inventory;
+
+/// Transactions in their stem phase, kept apart from the ones
+/// we announce, so that nobody can learn we have them.
+///
+/// Each one has an embargo: if it was not broadcast by then,
+/// we broadcast it ourselves, in case the stem path is broken.
+#[derive(Debug, Default)]
+pub struct StemPool {
+    embargoes: HashMap<Inventory, Instant>,
+}
+
+impl StemPool {
+    pub fn new() -> Self {
+        StemPool {
+            embargoes: HashMap::new(),
+        }
+    }
+
+    /// Adds a transaction, returns false if it was already there.
+    pub fn insert(&mut self, item: Inventory, embargo: Instant) -> bool {
+        if self.embargoes.contains_key(&item) {
+            return false;
+        }
+        self.embargoes.insert(item, embargo);
+        true
+    }
+
+    pub fn contains(&self, item: &Inventory) -> bool {
+        self.embargoes.contains_key(item)
+    }
+
+    pub fn remove(&mut self, item: &Inventory) -> bool {
+        self.embargoes.remove(item).is_some()
+    }
+
+    /// Removes and gives back the transactions whose embargo is over,
+    /// in a stable order.
+    pub fn expired(&mut self, now: Instant) -> Vec<Inventory> {
+        let mut expired: Vec<Inventory> = self.embargoes.iter()
+            .filter(|(_, embargo)| **embargo <= now)
+            .map(|(item, _)| *item)
+            .collect();
+        expired.sort_by_key(|item| item.hash);
+
+        for item in expired.iter() {
+            self.embargoes.remove(item);
+        }
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.embargoes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.embargoes.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::inv::InvType;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stem_pool() {
+        let now = Instant::now();
+        let (a, b) = (Inventory::new(InvType::Transaction, [1; 32]),
+            Inventory::new(InvType::Transaction, [2; 32]));
+        let mut pool = StemPool::new();
+        assert!(pool.insert(a, now + Duration::from_secs(10)));
+        assert!(!pool.insert(a, now));
+        assert!(pool.insert(b, now + Duration::from_secs(20)));
+
+        assert!(pool.expired(now).is_empty());
+        assert_eq!(pool.expired(now + Duration::from_secs(15)), vec![a]);
+        assert!(!pool.contains(&a));
+        assert!(pool.remove(&b));
+        assert!(pool.is_empty());
+    }
+}
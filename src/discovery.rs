@@ -0,0 +1,115 @@
+// Discovery of our own external address.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::netgroup::netgroup;
+
+/// Keeps track of the IP each peer sees us connecting from.
+///
+/// Behind a NAT or inside a container, the address of our local socket
+/// is a private one that other nodes can not reach. Instead we ask peers
+/// which address they see, and keep the one most of them agree on.
+/// Each network group has a single vote, so that one host opening
+/// many connections can not choose our address.
+#[derive(Debug, Default)]
+pub struct ExternalAddress {
+    votes: HashMap<Vec<u8>, (SocketAddr, IpAddr)>,  // netgroup -> last peer and the IP it reported
+}
+
+impl ExternalAddress {
+    pub fn new() -> Self {
+        ExternalAddress {
+            votes: HashMap::new(),
+        }
+    }
+
+    /// Save the IP reported by a peer.
+    /// A network group only has one vote: a new report from any peer
+    /// of the group replaces the previous one.
+    pub fn report(&mut self, peer: SocketAddr, ip: IpAddr) {
+        if ip.is_unspecified() {
+            return;
+        }
+
+        self.votes.insert(netgroup(&peer.ip()), (peer, ip));
+    }
+
+    /// Remove the vote of a disconnected peer, if it still holds
+    /// the vote of its group.
+    pub fn forget(&mut self, peer: &SocketAddr) {
+        let group = netgroup(&peer.ip());
+        if self.votes.get(&group).map(|(voter, _)| voter) == Some(peer) {
+            self.votes.remove(&group);
+        }
+    }
+
+    /// The IP reported by the most peers, if any.
+    /// Ties are broken by taking the lowest IP, so that the result
+    /// does not depend on the order of the reports.
+    pub fn best(&self) -> Option<IpAddr> {
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for (_, ip) in self.votes.values() {
+            *counts.entry(*ip).or_insert(0) += 1;
+        }
+
+        counts.into_iter()
+            .max_by(|(ip_a, count_a), (ip_b, count_b)| {
+                count_a.cmp(count_b).then(ip_b.cmp(ip_a))
+            })
+            .map(|(ip, _)| ip)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A peer in a network group of its own.
+    fn peer(group: u8) -> SocketAddr {
+        SocketAddr::new(IpAddr::from([1, group, 0, 1]), 8333)
+    }
+
+    #[test]
+    fn test_majority_vote() {
+        let mut external = ExternalAddress::new();
+        assert_eq!(external.best(), None);
+
+        external.report(peer(1), "1.2.3.4".parse().unwrap());
+        external.report(peer(2), "5.6.7.8".parse().unwrap());
+        external.report(peer(3), "5.6.7.8".parse().unwrap());
+        assert_eq!(external.best(), Some("5.6.7.8".parse().unwrap()));
+
+        external.forget(&peer(3));
+        external.report(peer(2), "1.2.3.4".parse().unwrap());
+        assert_eq!(external.best(), Some("1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_tie_is_deterministic() {
+        let mut external = ExternalAddress::new();
+        external.report(peer(1), "5.6.7.8".parse().unwrap());
+        external.report(peer(2), "1.2.3.4".parse().unwrap());
+        external.report(peer(3), "0.0.0.0".parse().unwrap());
+        assert_eq!(external.best(), Some("1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_one_vote_per_group() {
+        let mut external = ExternalAddress::new();
+        external.report(peer(1), "1.2.3.4".parse().unwrap());
+        external.report(peer(2), "1.2.3.4".parse().unwrap());
+        // Many connections from the same /16 only count once.
+        for port in 1..10 {
+            let sybil = SocketAddr::new("9.9.0.1".parse().unwrap(), port);
+            external.report(sybil, "5.6.7.8".parse().unwrap());
+        }
+        assert_eq!(external.best(), Some("1.2.3.4".parse().unwrap()));
+
+        // The vote of the group stays until its last voter leaves.
+        external.forget(&SocketAddr::new("9.9.0.1".parse().unwrap(), 1));
+        assert_eq!(external.votes.len(), 3);
+        external.forget(&SocketAddr::new("9.9.0.1".parse().unwrap(), 9));
+        assert_eq!(external.votes.len(), 2);
+    }
+}
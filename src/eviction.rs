@@ -0,0 +1,137 @@
+// Choice of the inbound node to disconnect when all slots are taken.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use mio::Token;
+
+/// A node that relayed a new block within this time is never evicted.
+pub const BLOCK_RELAY_PROTECTION: Duration = Duration::from_secs(30 * 60);
+/// Number of nodes with the lowest latency kept.
+pub const PROTECTED_BY_LATENCY: usize = 4;
+/// Number of nodes which most recently sent useful messages kept.
+pub const PROTECTED_BY_USEFULNESS: usize = 4;
+
+/// What the eviction needs to know about an inbound node.
+#[derive(Debug, Clone)]
+pub struct EvictionCandidate {
+    pub token: Token,
+    pub latency: Option<Duration>,  // None while unknown.
+    pub last_useful: Option<Instant>,  // Last new inventory or addresses received.
+    pub last_block_relay: Option<Instant>,
+    pub netgroup: Vec<u8>,
+}
+
+/// Selects the node to evict, if any.
+///
+/// Nodes that recently relayed blocks, the fastest ones and the ones
+/// which recently sent useful messages are protected. The victim is then
+/// taken from the most represented network group: it is the one with the
+/// worst latency, or the one useful for the longest time.
+pub fn select_node_to_evict(mut candidates: Vec<EvictionCandidate>, now: Instant)
+    -> Option<Token> {
+    candidates.retain(|c| match c.last_block_relay {
+        Some(time) => now.duration_since(time) > BLOCK_RELAY_PROTECTION,
+        None => true,
+    });
+
+    // Unknown latencies are the worst ones.
+    candidates.sort_by_key(|c| (c.latency.is_none(), c.latency));
+    protect_first(&mut candidates, PROTECTED_BY_LATENCY);
+
+    // Most recently useful first.
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.last_useful));
+    protect_first(&mut candidates, PROTECTED_BY_USEFULNESS);
+
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let mut groups: HashMap<Vec<u8>, Vec<EvictionCandidate>> = HashMap::new();
+    for candidate in candidates {
+        groups.entry(candidate.netgroup.clone()).or_default().push(candidate);
+    }
+
+    let (_, group) = groups.into_iter()
+        .max_by(|(group_a, a), (group_b, b)| a.len().cmp(&b.len()).then(group_b.cmp(group_a)))?;
+
+    group.into_iter()
+        .max_by_key(|c| (c.latency.is_none(), c.latency, std::cmp::Reverse(c.last_useful)))
+        .map(|c| c.token)
+}
+
+/// Removes the `count` first candidates, they can not be evicted.
+fn protect_first(candidates: &mut Vec<EvictionCandidate>, count: usize) {
+    let count = count.min(candidates.len());
+    candidates.drain(..count);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(token: usize, latency: u64, netgroup: u8) -> EvictionCandidate {
+        EvictionCandidate {
+            token: Token(token),
+            latency: Some(Duration::from_millis(latency)),
+            last_useful: None,
+            last_block_relay: None,
+            netgroup: vec![4, netgroup, 0],
+        }
+    }
+
+    #[test]
+    fn test_nothing_to_evict() {
+        let now = Instant::now();
+        let candidates = (0..PROTECTED_BY_LATENCY)
+            .map(|i| candidate(i, 10, 1))
+            .collect();
+        assert_eq!(select_node_to_evict(candidates, now), None);
+    }
+
+    #[test]
+    fn test_evict_from_biggest_group() {
+        let now = Instant::now();
+        let mut candidates: Vec<EvictionCandidate> = (0..PROTECTED_BY_LATENCY)
+            .map(|i| candidate(i, 1, 1))
+            .collect();
+        for i in 0..PROTECTED_BY_USEFULNESS {
+            let mut c = candidate(10 + i, 500, 2);
+            c.last_useful = Some(now);
+            candidates.push(c);
+        }
+        candidates.push(candidate(20, 900, 3));
+        candidates.push(candidate(21, 100, 4));
+        candidates.push(candidate(22, 200, 4));
+
+        assert_eq!(select_node_to_evict(candidates, now), Some(Token(22)));
+    }
+
+    #[test]
+    fn test_block_relay_protection() {
+        let now = Instant::now();
+        let mut candidates: Vec<EvictionCandidate> = (0..PROTECTED_BY_LATENCY + PROTECTED_BY_USEFULNESS)
+            .map(|i| candidate(i, 1, 1))
+            .collect();
+        let mut relay = candidate(20, 900, 3);
+        relay.last_block_relay = Some(now);
+        candidates.push(relay);
+        candidates.push(candidate(21, 100, 3));
+
+        assert_eq!(select_node_to_evict(candidates, now), Some(Token(21)));
+    }
+
+    #[test]
+    fn test_unknown_latency_is_worst() {
+        let now = Instant::now();
+        let mut candidates: Vec<EvictionCandidate> = (0..PROTECTED_BY_LATENCY + PROTECTED_BY_USEFULNESS)
+            .map(|i| candidate(i, 1, 1))
+            .collect();
+        let mut unknown = candidate(20, 0, 3);
+        unknown.latency = None;
+        candidates.push(unknown);
+        candidates.push(candidate(21, 800, 3));
+
+        assert_eq!(select_node_to_evict(candidates, now), Some(Token(20)));
+    }
+}
@@ -0,0 +1,87 @@
+// Sets of inventory items, forgetting the oldest ones.
+use std::collections::HashSet;
+use std::mem;
+
+use crate::messages::inv::Inventory;
+
+/// Remembers about the last `capacity` items inserted.
+///
+/// The items go into a current generation; once it holds half the
+/// capacity, it becomes the previous one and the oldest generation
+/// is forgotten. Between `capacity / 2` and `capacity` items are kept.
+#[derive(Debug, Clone)]
+pub struct RollingFilter {
+    capacity: usize,
+    current: HashSet<Inventory>,
+    previous: HashSet<Inventory>,
+}
+
+impl RollingFilter {
+    pub fn new(capacity: usize) -> Self {
+        RollingFilter {
+            capacity: capacity.max(2),
+            current: HashSet::new(),
+            previous: HashSet::new(),
+        }
+    }
+
+    /// Adds the item, returns false if it was already known.
+    pub fn insert(&mut self, item: Inventory) -> bool {
+        if self.contains(&item) {
+            return false;
+        }
+
+        if self.current.len() >= self.capacity / 2 {
+            self.previous = mem::take(&mut self.current);
+        }
+        self.current.insert(item)
+    }
+
+    pub fn contains(&self, item: &Inventory) -> bool {
+        self.current.contains(item) || self.previous.contains(item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.current.clear();
+        self.previous.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::inv::InvType;
+
+    fn item(n: u8) -> Inventory {
+        Inventory::new(InvType::Transaction, [n; 32])
+    }
+
+    #[test]
+    fn test_rolling_filter() {
+        let mut filter = RollingFilter::new(10);
+        assert!(filter.insert(item(0)));
+        assert!(!filter.insert(item(0)));
+        assert!(filter.contains(&item(0)));
+
+        for n in 1..=10 {
+            filter.insert(item(n));
+        }
+        // The first generation is forgotten, the last items are kept.
+        assert!(!filter.contains(&item(0)));
+        assert!(filter.contains(&item(5)));
+        assert!(filter.contains(&item(10)));
+        assert!(filter.len() <= 10);
+
+        filter.clear();
+        assert!(filter.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+// Discovery of the nodes of the local network, through UDP broadcast.
+use std::convert::TryInto;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mio::net::UdpSocket;
+use mio::{Interest, Registry, Token};
+use snow::params::HashChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+
+use crate::config::LanConfig;
+use crate::messages::states::{BEACON_INTERVAL, MAX_BEACON_AGE};
+
+/// magic, port, reply, id, time and the signature.
+pub const BEACON_SIZE: usize = 4 + 2 + 1 + 8 + 8 + SIGNATURE_SIZE;
+const SIGNATURE_SIZE: usize = 32;
+/// Longest secret the signature accepts.
+pub const MAX_SECRET_SIZE: usize = 64;
+
+/// Tells the nodes of the local network where we listen.
+///
+/// Signed with a secret shared by the nodes of the network,
+/// the other beacons are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Beacon {
+    pub magic: u32,
+    pub port: u16,  // Port we listen on.
+    pub reply: bool,  // Answers a beacon, not answered back.
+    pub id: u64,  // Tells our own beacons apart.
+    pub time: u64,  // Unix time it was sent at, old beacons are ignored.
+}
+
+impl Beacon {
+    pub fn to_bytes(&self, secret: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BEACON_SIZE);
+        bytes.extend(&self.magic.to_be_bytes());
+        bytes.extend(&self.port.to_be_bytes());
+        bytes.push(self.reply as u8);
+        bytes.extend(&self.id.to_be_bytes());
+        bytes.extend(&self.time.to_be_bytes());
+        let signature = sign(secret, &bytes);
+        bytes.extend(&signature);
+        bytes
+    }
+
+    /// Reads a beacon, checking its signature.
+    pub fn from_bytes(bytes: &[u8], secret: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() != BEACON_SIZE {
+            return Err("Wrong beacon size");
+        }
+
+        let (data, signature) = bytes.split_at(BEACON_SIZE - SIGNATURE_SIZE);
+        let expected = sign(secret, data);
+        // Compares in constant time.
+        if expected.iter().zip(signature).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+            return Err("Invalid beacon signature");
+        }
+
+        Ok(Beacon {
+            magic: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+            port: u16::from_be_bytes(data[4..6].try_into().unwrap()),
+            reply: data[6] != 0,
+            id: u64::from_be_bytes(data[7..15].try_into().unwrap()),
+            time: u64::from_be_bytes(data[15..23].try_into().unwrap()),
+        })
+    }
+}
+
+/// HMAC-BLAKE2s of the data.
+fn sign(secret: &[u8], data: &[u8]) -> [u8; SIGNATURE_SIZE] {
+    let mut hash = DefaultResolver.resolve_hash(&HashChoice::Blake2s)
+        .expect("BLAKE2s is supported");
+    let mut signature = [0; SIGNATURE_SIZE];
+    hash.hmac(secret, data, &mut signature);
+    signature
+}
+
+/// Sends our beacon at `BEACON_INTERVAL` and answers the ones
+/// of the other nodes, so that the nodes not listening on
+/// the broadcast port still learn about us.
+#[derive(Debug)]
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    broadcast: SocketAddr,
+    secret: Vec<u8>,
+    magic: u32,
+    id: u64,
+    next_beacon: Instant,
+}
+
+impl LanDiscovery {
+    pub fn bind(config: &LanConfig, magic: u32, id: u64, now: Instant) -> io::Result<Self> {
+        let socket = UdpSocket::bind(config.bind)?;
+        socket.set_broadcast(true)?;
+        Ok(LanDiscovery {
+            socket,
+            broadcast: config.broadcast,
+            secret: config.secret.clone(),
+            magic,
+            id,
+            next_beacon: now,
+        })
+    }
+
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(&mut self.socket, token, Interest::READABLE)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Broadcasts our beacon when it is time to.
+    pub fn routine(&mut self, now: Instant, unix: u64, port: u16) -> io::Result<()> {
+        if now < self.next_beacon {
+            return Ok(());
+        }
+        self.next_beacon = now + Duration::from_secs(BEACON_INTERVAL.into());
+        self.send(self.broadcast, false, unix, port)
+    }
+
+    /// Reads the beacons received, answers them,
+    /// and gives back the addresses the nodes listen on.
+    pub fn receive(&mut self, unix: u64, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let mut found = Vec::new();
+        let mut bytes = [0; BEACON_SIZE + 1];  // Bigger datagrams are spotted.
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut bytes) {
+                Ok(received) => received,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                // A beacon we sent was not received.
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(err) => return Err(err),
+            };
+
+            let beacon = match Beacon::from_bytes(&bytes[..size], &self.secret) {
+                Ok(beacon) => beacon,
+                Err(err) => {
+                    println!("Ignoring a beacon from {}: {}", from, err);
+                    continue;
+                },
+            };
+            if beacon.magic != self.magic || beacon.id == self.id {
+                continue;
+            }
+            // Too old, or from the future: a replayed beacon either way.
+            let max_age = u64::from(MAX_BEACON_AGE);
+            if beacon.time > unix.saturating_add(max_age) || unix.saturating_sub(beacon.time) > max_age {
+                println!("Ignoring an outdated beacon from {}", from);
+                continue;
+            }
+
+            if !beacon.reply {
+                if let Err(err) = self.send(from, true, unix, port) {
+                    println!("Could not answer the beacon of {}: {}", from, err);
+                }
+            }
+            found.push(SocketAddr::new(from.ip(), beacon.port));
+        }
+        Ok(found)
+    }
+
+    fn send(&self, to: SocketAddr, reply: bool, unix: u64, port: u16) -> io::Result<()> {
+        let beacon = Beacon {
+            magic: self.magic,
+            port,
+            reply,
+            id: self.id,
+            time: unix,
+        };
+        self.socket.send_to(&beacon.to_bytes(&self.secret), to)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beacon() {
+        let beacon = Beacon { magic: 42, port: 8000, reply: false, id: 7, time: 1000 };
+        let bytes = beacon.to_bytes(b"secret");
+        assert_eq!(bytes.len(), BEACON_SIZE);
+        assert_eq!(Beacon::from_bytes(&bytes, b"secret"), Ok(beacon));
+        assert!(Beacon::from_bytes(&bytes, b"other").is_err());
+
+        let mut forged = bytes.clone();
+        forged[5] ^= 1;
+        assert!(Beacon::from_bytes(&forged, b"secret").is_err());
+        assert!(Beacon::from_bytes(&bytes[1..], b"secret").is_err());
+    }
+
+    #[test]
+    fn test_beacon_age() {
+        let config = LanConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            broadcast: "127.0.0.1:9".parse().unwrap(),
+            secret: b"secret".to_vec(),
+        };
+        let mut lan = LanDiscovery::bind(&config, 42, 1, Instant::now()).unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let unix = 1_000_000;
+        let max_age = u64::from(MAX_BEACON_AGE);
+        for time in [0, unix - max_age - 1, unix + max_age + 1, u64::MAX, unix - max_age, unix + max_age] {
+            let beacon = Beacon { magic: 42, port: 8000, reply: true, id: 2, time };
+            sender.send_to(&beacon.to_bytes(b"secret"), lan.local_addr().unwrap()).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(100));
+
+        // Only the last two are recent enough.
+        let found = lan.receive(unix, 8000).unwrap();
+        assert_eq!(found, vec!["127.0.0.1:8000".parse().unwrap(); 2]);
+    }
+}
@@ -0,0 +1,23 @@
+pub mod server;
+pub mod node;
+pub mod messages;
+pub mod config;
+pub mod discovery;
+pub mod time;
+pub mod outbound;
+pub mod address_book;
+pub mod netgroup;
+pub mod eviction;
+pub mod control;
+pub mod socks5;
+pub mod noise;
+pub mod transport;
+pub mod memory;
+pub mod simulator;
+pub mod workers;
+pub mod slab;
+pub mod inventory;
+pub mod random;
+pub mod dandelion;
+pub mod bandwidth;
+pub mod lan;
@@ -1,10 +1,21 @@
-mod server;
-mod node;
-mod messages;
-
-use server::Server;
+use rustycoin::config::{EncryptionPolicy, ServerConfig};
+use rustycoin::messages::states::{DEFAULT_LAN_BIND, DEFAULT_LAN_BROADCAST, LAN_SECRET_VAR};
+use rustycoin::server::Server;
 
 fn main() {
-    let mut server = Server::new("127.0.0.1:8000").unwrap();
+    let secret = std::env::var(LAN_SECRET_VAR).ok();
+    // Only reachable from the local network when looking for its nodes.
+    let listen = if secret.is_some() { "0.0.0.0:8000" } else { "127.0.0.1:8000" };
+    let mut config = ServerConfig::builder()
+        .listen(listen)
+        .handle_signals(true)
+        .encryption(EncryptionPolicy::Preferred);
+    // The nodes of the local network share a secret of their own.
+    if let Some(secret) = &secret {
+        config = config.lan_discovery(DEFAULT_LAN_BIND, DEFAULT_LAN_BROADCAST, secret);
+    }
+    let config = config.build().unwrap();
+
+    let mut server = Server::new(config).unwrap();
     server.launch().unwrap();
 }
@@ -0,0 +1,375 @@
+// In-memory network, to run nodes in a single process without sockets.
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use mio::event::Source;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+
+use crate::transport::{Network, Stream};
+
+/// First port given to the sockets bound or connected without a port.
+pub const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// Data going one way.
+#[derive(Debug, Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Makes the poll a reader is registered in ready.
+///
+/// The actual data is kept in memory, a byte is sent through
+/// an OS pipe only to wake the poll up.
+#[derive(Debug)]
+pub struct Notifier {
+    pub sender: pipe::Sender,
+    pub receiver: pipe::Receiver,  // Registered in the poll.
+}
+
+impl Notifier {
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = pipe::new()?;
+        Ok(Notifier { sender, receiver })
+    }
+}
+
+/// Wakes up the poll the receiver is registered in.
+pub fn notify(mut sender: &pipe::Sender) {
+    // A full pipe means the reader has not been woken up yet.
+    let _ = sender.write(&[0]);
+}
+
+/// Consumes the wake-ups, before reading.
+pub fn clear(mut receiver: &pipe::Receiver) {
+    let mut buffer = [0; 64];
+    while let Ok(n) = receiver.read(&mut buffer) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+/// One end of an in-memory duplex stream.
+#[derive(Debug)]
+pub struct MemoryStream {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+    readable: pipe::Receiver,  // Woken up when the peer writes.
+    notify_peer: pipe::Sender,
+}
+
+impl MemoryStream {
+    /// Creates the two connected ends of a stream.
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> io::Result<(MemoryStream, MemoryStream)> {
+        let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+        let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+        let (a_notifier, b_notifier) = (Notifier::new()?, Notifier::new()?);
+
+        let a_end = MemoryStream {
+            local_addr: a,
+            peer_addr: b,
+            incoming: Arc::clone(&b_to_a),
+            outgoing: Arc::clone(&a_to_b),
+            readable: a_notifier.receiver,
+            notify_peer: b_notifier.sender,
+        };
+        let b_end = MemoryStream {
+            local_addr: b,
+            peer_addr: a,
+            incoming: a_to_b,
+            outgoing: b_to_a,
+            readable: b_notifier.receiver,
+            notify_peer: a_notifier.sender,
+        };
+        Ok((a_end, b_end))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        clear(&self.readable);
+
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.data.is_empty() {
+            return if incoming.closed {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            };
+        }
+
+        let n = buf.len().min(incoming.data.len());
+        for (byte, data) in buf.iter_mut().zip(incoming.data.drain(..n)) {
+            *byte = data;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        {
+            let mut outgoing = self.outgoing.lock().unwrap();
+            if outgoing.closed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            outgoing.data.extend(buf);
+        }
+
+        notify(&self.notify_peer);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.incoming.lock().unwrap().closed = true;
+        self.outgoing.lock().unwrap().closed = true;
+        notify(&self.notify_peer);
+    }
+}
+
+/// Only the readable interest is reported, writing never blocks.
+impl Source for MemoryStream {
+    fn register(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        self.readable.register(registry, token, Interest::READABLE)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        self.readable.reregister(registry, token, Interest::READABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.readable.deregister(registry)
+    }
+}
+
+impl Stream for MemoryStream {}
+
+/// Connections waiting to be accepted.
+#[derive(Debug)]
+struct Backlog {
+    pending: VecDeque<(MemoryStream, SocketAddr)>,
+    notify: pipe::Sender,
+}
+
+#[derive(Debug)]
+pub struct MemoryListener {
+    addr: SocketAddr,
+    backlog: Arc<Mutex<Backlog>>,
+    readable: pipe::Receiver,
+    network: MemoryNetwork,
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.network.listeners.lock().unwrap().remove(&self.addr);
+    }
+}
+
+impl Source for MemoryListener {
+    fn register(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        self.readable.register(registry, token, Interest::READABLE)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        self.readable.reregister(registry, token, Interest::READABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.readable.deregister(registry)
+    }
+}
+
+/// A network living in memory.
+///
+/// Clones share the same listeners, `host` gives a clone
+/// whose connections come from another IP.
+#[derive(Debug, Clone)]
+pub struct MemoryNetwork {
+    ip: IpAddr,  // Source IP of the connections made through this handle.
+    listeners: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<Backlog>>>>>,
+    next_port: Arc<Mutex<u16>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        MemoryNetwork {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            listeners: Arc::new(Mutex::new(HashMap::new())),
+            next_port: Arc::new(Mutex::new(FIRST_EPHEMERAL_PORT)),
+        }
+    }
+
+    /// Handle on the same network, for a host with this IP.
+    pub fn host(&self, ip: IpAddr) -> Self {
+        MemoryNetwork {
+            ip,
+            ..self.clone()
+        }
+    }
+
+    fn ephemeral_port(&self) -> u16 {
+        let mut next_port = self.next_port.lock().unwrap();
+        let port = *next_port;
+        *next_port = next_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+        port
+    }
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Network for MemoryNetwork {
+    type Stream = MemoryStream;
+    type Listener = MemoryListener;
+
+    fn bind(&self, mut addr: SocketAddr) -> io::Result<MemoryListener> {
+        if addr.port() == 0 {
+            addr.set_port(self.ephemeral_port());
+        }
+
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+
+        let notifier = Notifier::new()?;
+        let backlog = Arc::new(Mutex::new(Backlog {
+            pending: VecDeque::new(),
+            notify: notifier.sender,
+        }));
+        listeners.insert(addr, Arc::clone(&backlog));
+
+        Ok(MemoryListener {
+            addr,
+            backlog,
+            readable: notifier.receiver,
+            network: self.clone(),
+        })
+    }
+
+    fn accept(&self, listener: &MemoryListener) -> io::Result<(MemoryStream, SocketAddr)> {
+        clear(&listener.readable);
+        let mut backlog = listener.backlog.lock().unwrap();
+        match backlog.pending.pop_front() {
+            Some(connection) => Ok(connection),
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn connect(&self, addr: SocketAddr) -> io::Result<MemoryStream> {
+        let backlog = match self.listeners.lock().unwrap().get(&addr) {
+            Some(backlog) => Arc::clone(backlog),
+            None => return Err(io::ErrorKind::ConnectionRefused.into()),
+        };
+
+        let local_addr = SocketAddr::new(self.ip, self.ephemeral_port());
+        let (local, remote) = MemoryStream::pair(local_addr, addr)?;
+
+        let mut backlog = backlog.lock().unwrap();
+        backlog.pending.push_back((remote, local_addr));
+        notify(&backlog.notify);
+        Ok(local)
+    }
+
+    fn local_addr(&self, listener: &MemoryListener) -> io::Result<SocketAddr> {
+        Ok(listener.addr)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_pair() {
+        let (mut a, mut b) = MemoryStream::pair(addr("10.0.0.1:1"), addr("10.0.0.2:2")).unwrap();
+        assert_eq!(b.peer_addr(), addr("10.0.0.1:1"));
+
+        let mut buffer = [0; 8];
+        assert_eq!(b.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        a.write_all(b"hello").unwrap();
+        assert_eq!(b.read(&mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"hello");
+
+        b.write_all(b"world").unwrap();
+        drop(b);
+        // The data written before closing can still be read.
+        assert_eq!(a.read(&mut buffer).unwrap(), 5);
+        assert_eq!(a.read(&mut buffer).unwrap(), 0);
+        assert!(a.write(b"!").is_err());
+    }
+
+    #[test]
+    fn test_network() {
+        let network = MemoryNetwork::new();
+        let listener = network.bind(addr("10.0.0.1:8000")).unwrap();
+        assert!(network.bind(addr("10.0.0.1:8000")).is_err());
+        assert!(network.connect(addr("10.0.0.1:8001")).is_err());
+
+        let host = network.host("10.0.0.2".parse().unwrap());
+        let mut client = host.connect(addr("10.0.0.1:8000")).unwrap();
+        let (mut server, peer_addr) = network.accept(&listener).unwrap();
+        assert_eq!(peer_addr.ip(), "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(client.local_addr(), peer_addr);
+        assert!(network.accept(&listener).is_err());
+
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        let ephemeral = network.bind(addr("10.0.0.1:0")).unwrap();
+        assert_ne!(network.local_addr(&ephemeral).unwrap().port(), 0);
+
+        drop(listener);
+        assert!(network.bind(addr("10.0.0.1:8000")).is_ok());
+    }
+
+    #[test]
+    fn test_poll() {
+        use mio::{Events, Poll};
+        use std::time::Duration;
+
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+        let (mut a, mut b) = MemoryStream::pair(addr("10.0.0.1:1"), addr("10.0.0.2:2")).unwrap();
+        poll.registry().register(&mut b, Token(1), Interest::READABLE).unwrap();
+
+        a.write_all(b"hello").unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.iter().next().map(|e| e.token()), Some(Token(1)));
+
+        let mut buffer = [0; 8];
+        assert_eq!(b.read(&mut buffer).unwrap(), 5);
+        poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        assert!(events.is_empty());
+    }
+}
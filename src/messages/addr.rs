@@ -0,0 +1,104 @@
+use std::convert::TryFrom;
+
+use super::address::{ADDRESS_SIZE, Address};
+use super::var_uint::VarUint;
+use super::ByteSize;
+
+/// Maximum number of addresses in an addr message.
+pub const MAX_ADDR_SIZE: u64 = 1000;
+
+/// List of known node addresses, shared between peers.
+#[derive(Debug, PartialEq)]
+pub struct Addr {
+    pub count: VarUint,
+    pub addresses: Vec<Address>,
+}
+
+impl Addr {
+    pub fn new(addresses: Vec<Address>) -> Self {
+        Addr {
+            count: VarUint::new(addresses.len() as u64),
+            addresses,
+        }
+    }
+}
+
+impl ByteSize for Addr {
+    fn byte_size(&self) -> usize {
+        self.count.byte_size() +
+            self.addresses.iter().map(|a| a.byte_size()).sum::<usize>()
+    }
+}
+
+impl TryFrom<&[u8]> for Addr {
+    type Error = &'static str;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let count = VarUint::try_from(bytes)?;
+        let (_, mut bytes) = bytes.split_at(count.byte_size());
+
+        if count.value() > MAX_ADDR_SIZE {
+            return Err("Too many addresses");
+        }
+        match count.value().checked_mul(ADDRESS_SIZE as u64) {
+            Some(length) if bytes.len() as u64 >= length => (),
+            _ => return Err("Slice is not big enough"),
+        }
+
+        let mut addresses: Vec<Address> = Vec::new();
+        for _ in 0..count.value() {
+            let (addr, b) = bytes.split_at(ADDRESS_SIZE);
+            addresses.push(Address::try_from(addr)?);
+            bytes = b;
+        }
+
+        Ok(Addr{count, addresses})
+    }
+}
+
+impl From<Addr> for Vec<u8> {
+    fn from(addr: Addr) -> Self {
+        let mut bytes: Vec<u8> = Vec::<u8>::from(addr.count);
+        for address in addr.addresses {
+            bytes.extend(Vec::<u8>::from(address));
+        }
+
+        bytes
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_addr() {
+        let addresses = vec![
+            Address::new(431, "127.0.0.1".parse().unwrap(), 10),
+            Address::new(12, "10.0.0.3".parse().unwrap(), 8000),
+        ];
+        let addr = Addr::new(addresses.clone());
+        let bytes = Vec::<u8>::from(addr);
+        assert_eq!(Addr::try_from(bytes.as_slice()), Ok(Addr::new(addresses)));
+    }
+
+    #[test]
+    fn test_addr_too_short() {
+        let addr = Addr::new(vec![Address::new(431, "127.0.0.1".parse().unwrap(), 10)]);
+        let bytes = Vec::<u8>::from(addr);
+        assert!(Addr::try_from(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_addr_too_many() {
+        // Would overflow the expected length.
+        let mut bytes = vec![0xFF];
+        bytes.extend(&0x9D89_D89D_89D8_9D8A_u64.to_be_bytes());
+        assert_eq!(Addr::try_from(bytes.as_slice()), Err("Too many addresses"));
+
+        let mut bytes = Vec::<u8>::from(VarUint::new(MAX_ADDR_SIZE + 1));
+        bytes.extend(vec![0; ADDRESS_SIZE * (MAX_ADDR_SIZE as usize + 1)]);
+        assert_eq!(Addr::try_from(bytes.as_slice()), Err("Too many addresses"));
+    }
+}
@@ -1,9 +1,13 @@
 use std::convert::TryFrom;
 use std::convert::TryInto;
-use std::net::{IpAddr, Ipv6Addr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
 use super::ByteSize;
+use crate::netgroup::{self, Reachability};
 
+/// Address of a node, as sent over the network.
+///
+/// IPv4 addresses are stored as IPv4-mapped IPv6 addresses.
 #[derive(Debug, PartialEq, Clone)]
 pub struct Address {
     timestamp: u64,
@@ -16,7 +20,7 @@ pub const ADDRESS_SIZE: usize = 8 + 16 + 2;
 impl Address {
     pub fn new(timestamp: u64, addr: IpAddr, port: u16) -> Self {
         let addr: Ipv6Addr = match addr {
-            IpAddr::V4(addr) => addr.to_ipv6_compatible(),
+            IpAddr::V4(addr) => addr.to_ipv6_mapped(),
             IpAddr::V6(addr) => addr,
         };
 
@@ -26,6 +30,58 @@ impl Address {
             port,
         }
     }
+
+    pub fn from_socket_addr(timestamp: u64, addr: &SocketAddr) -> Self {
+        Address::new(timestamp, addr.ip(), addr.port())
+    }
+
+    /// Address to connect to the node.
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip(), self.port)
+    }
+
+    /// IP as it is sent over the network.
+    pub fn ipv6(&self) -> Ipv6Addr {
+        self.addr
+    }
+
+    /// IP of the node, with IPv4 addresses given back as such.
+    pub fn ip(&self) -> IpAddr {
+        match self.addr.to_ipv4_mapped() {
+            Some(addr) => IpAddr::V4(addr),
+            None => IpAddr::V6(self.addr),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn reachability(&self) -> Reachability {
+        netgroup::reachability(&self.ip())
+    }
+
+    /// Whether nodes from the Internet could connect to this address.
+    pub fn is_routable(&self) -> bool {
+        self.port != 0 && self.reachability() == Reachability::Routable
+    }
+}
+
+/// The timestamp is set to 0.
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::from_socket_addr(0, &addr)
+    }
+}
+
+impl From<&Address> for SocketAddr {
+    fn from(addr: &Address) -> Self {
+        addr.socket_addr()
+    }
 }
 
 impl ByteSize for Address {
@@ -82,4 +138,42 @@ mod tests {
         assert_eq!(Address::try_from(bytes.as_slice()),
             Ok(Address::new(431, "127.0.0.1".parse().unwrap(), 10)));
     }
+
+    #[test]
+    fn test_socket_addr() {
+        for addr in ["1.2.3.4:8000", "[2a01:e0a::1]:8333", "[::1]:1", "0.0.0.0:0"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let address = Address::from_socket_addr(12, &addr);
+            assert_eq!(address.timestamp(), 12);
+            assert_eq!(address.socket_addr(), addr);
+            assert_eq!(SocketAddr::from(&Address::from(addr)), addr);
+        }
+
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:8000".parse().unwrap();
+        assert_eq!(Address::from(mapped).socket_addr(), "1.2.3.4:8000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_routable() {
+        let address = |addr: &str| Address::from(addr.parse::<SocketAddr>().unwrap());
+        assert!(address("1.2.3.4:8000").is_routable());
+        assert!(!address("1.2.3.4:0").is_routable());
+        assert!(!address("127.0.0.1:8000").is_routable());
+        assert_eq!(address("192.168.0.1:8000").reachability(), Reachability::Private);
+        assert_eq!(address("[::1]:8000").reachability(), Reachability::Local);
+    }
+
+    #[test]
+    fn test_ip_mapping() {
+        let ipv4 = Address::new(0, "1.2.3.4".parse().unwrap(), 8000);
+        let bytes = Vec::<u8>::from(ipv4.clone());
+        assert_eq!(&bytes[8..24], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4]);
+        assert_eq!(ipv4.ip(), "1.2.3.4".parse::<IpAddr>().unwrap());
+
+        // IPv6 addresses are never mistaken for IPv4 ones.
+        for ip in ["::1", "::", "::102:304", "2001:db8::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert_eq!(Address::new(0, ip, 8000).ip(), ip);
+        }
+    }
 }
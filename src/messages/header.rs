@@ -31,7 +31,7 @@ impl Header {
         Ok(Header{magic, msg_type: msg_type.into(), length})
     }
 
-    pub fn read_buffer(buffer: &mut Vec<u8>) -> Option<(Self, Vec<u8>)> {
+    pub fn read_buffer(buffer: &[u8]) -> Option<(Self, Vec<u8>)> {
         if buffer.len() < HEADER_SIZE {
             return None;
         }
@@ -45,7 +45,7 @@ impl Header {
         }
     }
 
-    pub fn msg<'a>(&'a self) -> &'a String {
+    pub fn msg(&self) -> &String {
         &self.msg_type
     }
 }
@@ -70,9 +70,9 @@ impl TryFrom<&[u8]> for Header {
         let (msg_type, length) = bytes.split_at(12);
         let msg_type: Vec<u8> = msg_type.iter()
                                         .filter(|&&el| el != 0) // Removes the empty characters to avoid later errors
-                                        .map(|&el| el)
+                                        .copied()
                                         .collect();
-        let msg_type = match String::from_utf8(msg_type.into()) {
+        let msg_type = match String::from_utf8(msg_type) {
             Ok(val) if val.is_ascii() => val,
             _ => return Err("Non-ascii characters !"),
         };
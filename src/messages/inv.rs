@@ -0,0 +1,164 @@
+use std::convert::TryFrom;
+use std::convert::TryInto;
+
+use super::var_uint::VarUint;
+use super::ByteSize;
+
+pub const INVENTORY_SIZE: usize = 4 + 32;
+/// Maximum number of items in an inv message.
+pub const MAX_INV_SIZE: u64 = 50_000;
+
+/// Kind of an announced item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvType {
+    Transaction,
+    Block,
+}
+
+impl InvType {
+    fn value(&self) -> u32 {
+        match self {
+            InvType::Transaction => 1,
+            InvType::Block => 2,
+        }
+    }
+}
+
+impl TryFrom<u32> for InvType {
+    type Error = &'static str;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(InvType::Transaction),
+            2 => Ok(InvType::Block),
+            _ => Err("Unknown inventory type"),
+        }
+    }
+}
+
+/// A block or a transaction, identified by its hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub kind: InvType,
+    pub hash: [u8; 32],
+}
+
+impl Inventory {
+    pub fn new(kind: InvType, hash: [u8; 32]) -> Self {
+        Inventory { kind, hash }
+    }
+}
+
+impl ByteSize for Inventory {
+    fn byte_size(&self) -> usize {
+        INVENTORY_SIZE
+    }
+}
+
+impl TryFrom<&[u8]> for Inventory {
+    type Error = &'static str;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < INVENTORY_SIZE {
+            return Err("Slice is not big enough");
+        }
+
+        let (kind, bytes) = bytes.split_at(4);
+        let kind = InvType::try_from(u32::from_be_bytes(kind.try_into().unwrap()))?;
+        let hash: [u8; 32] = bytes[..32].try_into().unwrap();
+
+        Ok(Inventory { kind, hash })
+    }
+}
+
+impl From<Inventory> for Vec<u8> {
+    fn from(item: Inventory) -> Self {
+        let mut bytes = Vec::new();
+        bytes.extend(&u32::to_be_bytes(item.kind.value()));
+        bytes.extend(&item.hash);
+        bytes
+    }
+}
+
+/// Announces blocks and transactions.
+#[derive(Debug, PartialEq)]
+pub struct Inv {
+    pub count: VarUint,
+    pub items: Vec<Inventory>,
+}
+
+impl Inv {
+    pub fn new(items: Vec<Inventory>) -> Self {
+        Inv {
+            count: VarUint::new(items.len() as u64),
+            items,
+        }
+    }
+}
+
+impl ByteSize for Inv {
+    fn byte_size(&self) -> usize {
+        self.count.byte_size() + self.items.len() * INVENTORY_SIZE
+    }
+}
+
+impl TryFrom<&[u8]> for Inv {
+    type Error = &'static str;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let count = VarUint::try_from(bytes)?;
+        let (_, mut bytes) = bytes.split_at(count.byte_size());
+
+        if count.value() > MAX_INV_SIZE {
+            return Err("Too many items");
+        }
+        if (bytes.len() as u64) < count.value() * INVENTORY_SIZE as u64 {
+            return Err("Slice is not big enough");
+        }
+
+        let mut items: Vec<Inventory> = Vec::new();
+        for _ in 0..count.value() {
+            let (item, b) = bytes.split_at(INVENTORY_SIZE);
+            items.push(Inventory::try_from(item)?);
+            bytes = b;
+        }
+
+        Ok(Inv{count, items})
+    }
+}
+
+impl From<Inv> for Vec<u8> {
+    fn from(inv: Inv) -> Self {
+        let mut bytes: Vec<u8> = Vec::<u8>::from(inv.count);
+        for item in inv.items {
+            bytes.extend(Vec::<u8>::from(item));
+        }
+        bytes
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_inv() {
+        let items = vec![
+            Inventory::new(InvType::Transaction, [1; 32]),
+            Inventory::new(InvType::Block, [2; 32]),
+        ];
+        let inv = Inv::new(items.clone());
+        assert_eq!(inv.byte_size(), 1 + 2 * INVENTORY_SIZE);
+        let bytes = Vec::<u8>::from(inv);
+        assert_eq!(Inv::try_from(bytes.as_slice()), Ok(Inv::new(items)));
+        assert!(Inv::try_from(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        let mut bytes = Vec::<u8>::from(Inv::new(vec![Inventory::new(InvType::Block, [0; 32])]));
+        bytes[4] = 7;
+        assert!(Inv::try_from(bytes.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::convert::TryFrom;
+
+use super::states::*;
+use super::whoami::Whoami;
+use super::whoamiack::WhoamiAck;
+use super::addr::Addr;
+use super::inv::Inv;
+
+/// A complete message cut out of the stream,
+/// its payload not parsed yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub msg_type: String,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(msg_type: &str, payload: Vec<u8>) -> Self {
+        Frame {
+            msg_type: msg_type.to_string(),
+            payload,
+        }
+    }
+}
+
+/// A parsed message.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Ping,
+    Pong,
+    Whoami(Whoami),
+    WhoamiAck(Option<WhoamiAck>),  // The node may not tell us how it sees us.
+    Addr(Addr),
+    Inv(Inv),
+    StemTx(Inv),  // Transactions in their stem phase, not to be announced.
+    Goodbye,
+    Unknown(String),  // Type of the message, its payload is dropped.
+}
+
+/// Parses and checks the payload, which is the costly part
+/// of handling a message.
+impl TryFrom<&Frame> for Message {
+    type Error = &'static str;
+
+    fn try_from(frame: &Frame) -> Result<Self, Self::Error> {
+        let payload = frame.payload.as_slice();
+        let message = match frame.msg_type.as_str() {
+            PING_MSG => Message::Ping,
+            PONG_MSG => Message::Pong,
+            WHOAMI_MSG => Message::Whoami(Whoami::try_from(payload)?),
+            WHOAMIACK_MSG if payload.is_empty() => Message::WhoamiAck(None),
+            WHOAMIACK_MSG => Message::WhoamiAck(Some(WhoamiAck::try_from(payload)?)),
+            ADDR_MSG => Message::Addr(Addr::try_from(payload)?),
+            INV_MSG => Message::Inv(Inv::try_from(payload)?),
+            STEMTX_MSG => Message::StemTx(Inv::try_from(payload)?),
+            GOODBYE_MSG => Message::Goodbye,
+            msg_type => Message::Unknown(msg_type.to_string()),
+        };
+        Ok(message)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::address::Address;
+
+    #[test]
+    fn test_parse_message() {
+        assert_eq!(Message::try_from(&Frame::new(PING_MSG, Vec::new())), Ok(Message::Ping));
+        assert_eq!(Message::try_from(&Frame::new(WHOAMIACK_MSG, Vec::new())),
+            Ok(Message::WhoamiAck(None)));
+        assert_eq!(Message::try_from(&Frame::new("getdata", vec![1, 2])),
+            Ok(Message::Unknown("getdata".to_string())));
+
+        let addr = Addr::new(vec![Address::new(431, "10.0.0.3".parse().unwrap(), 8000)]);
+        let frame = Frame::new(ADDR_MSG, Vec::from(addr));
+        assert!(matches!(Message::try_from(&frame), Ok(Message::Addr(_))));
+
+        // A malformed payload is an error, not a panic.
+        assert!(Message::try_from(&Frame::new(WHOAMI_MSG, vec![0; 3])).is_err());
+        assert!(Message::try_from(&Frame::new(ADDR_MSG, vec![5])).is_err());
+    }
+}
@@ -1,5 +1,9 @@
 pub mod header;
 pub mod whoami;
+pub mod whoamiack;
+pub mod addr;
+pub mod inv;
+pub mod message;
 
 pub mod address;
 pub mod var_uint;
@@ -12,15 +12,21 @@ pub enum WhoamiSate {
 
 pub const WHOAMI_MSG: &str = "whoami";
 pub const WHOAMIACK_MSG: &str = "whoamiack";
+pub const ADDR_MSG: &str = "addr";
+pub const GOODBYE_MSG: &str = "goodbye";
+pub const INV_MSG: &str = "inv";
+pub const STEMTX_MSG: &str = "stemtx";
+pub const BLOCK_MSG: &str = "block";
 
 pub const VERSION: u32 = 0;
 pub const SERVICES: [&str; 1] = ["node"];
 
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum CurrentAction {
     WaitingHeader,  // Default mode : the node is waiting for a new message.
-    WaitingWhoami(u64),  // Whoami size
+    WaitingPayload(String, u64),  // Type and size of the message
 }
 
 #[derive(PartialEq)]
@@ -45,3 +51,77 @@ pub const LAST_SEEN_THRESHOLD: u32 = 10_800;  // 3 hours in secs
 
 /// Magic number, used in the header
 pub const MAGIC: u32 = 422021;
+
+/// Maximum time (in secs) the server waits for events
+/// before running the nodes' routines.
+pub const WAITING_TIME: u8 = 5;
+
+/// A node has this many secs to complete the whoami handshake.
+pub const HANDSHAKE_TIMEOUT: u32 = 60;
+
+pub const MAX_INBOUND: usize = 117;
+pub const MAX_OUTBOUND: usize = 11;
+/// Maximum number of inbound connections from a same IP.
+pub const MAX_PER_IP: usize = 4;
+/// Number of full-relay outbound connections the server tries to keep.
+pub const TARGET_OUTBOUND: usize = 8;
+/// Number of block-relay-only outbound connections the server tries to keep.
+pub const BLOCK_RELAY_ONLY: usize = 2;
+/// A feeler connection is made at this interval (in secs).
+pub const FEELER_INTERVAL: u32 = 120;
+
+/// Inventory items remembered as known by each node.
+pub const KNOWN_INVENTORY_SIZE: usize = 50_000;
+/// Recently rejected items, not requested nor relayed again.
+pub const REJECTED_INVENTORY_SIZE: usize = 120_000;
+
+/// Mean interval (in secs) between two announcements of
+/// transactions to an inbound node, which could be a spy.
+pub const INBOUND_TRICKLE_INTERVAL: u32 = 5;
+/// Mean interval (in secs) for the outbound nodes, which we chose.
+pub const OUTBOUND_TRICKLE_INTERVAL: u32 = 2;
+/// Maximum number of transactions announced at once.
+pub const MAX_TRICKLE_BATCH: usize = 1000;
+
+/// Chance for a node of the stem path to broadcast the transaction
+/// instead of passing it along.
+pub const FLUFF_PROBABILITY: f64 = 0.1;
+/// Mean time (in secs) after which a node broadcasts a transaction
+/// still in its stem phase.
+pub const STEM_EMBARGO: u32 = 30;
+
+/// A beacon is sent on the local network at this interval (in secs).
+pub const BEACON_INTERVAL: u32 = 30;
+/// Older beacons (in secs) are ignored.
+pub const MAX_BEACON_AGE: u32 = 120;
+
+/// Threads parsing and validating the messages, 0 to do it on the network thread.
+pub const WORKER_THREADS: usize = 4;
+/// Maximum bytes read from a node before serving the others.
+pub const MAX_READ_PER_TURN: usize = 64 * 1024;
+/// Bytes of messages moved at once to the connection, so that the
+/// messages queued with a higher priority do not wait too long.
+pub const MAX_WRITE_BUFFER: usize = 64 * 1024;
+/// Past these bytes read and not processed, a node is not read
+/// from anymore, until they are back under the low-water mark.
+pub const INPUT_HIGH_WATER: usize = 4 * 1024 * 1024;
+pub const INPUT_LOW_WATER: usize = 1024 * 1024;
+/// Longest payload accepted. Under the low-water mark, so that
+/// a message waiting for its end never keeps the reads paused.
+pub const MAX_MESSAGE_SIZE: u64 = 512 * 1024;
+/// Same for the messages of a node waiting for the workers.
+pub const PROCESSING_HIGH_WATER: usize = 1000;
+pub const PROCESSING_LOW_WATER: usize = 250;
+/// Longest wait (in millis) of the nodes held back by the bandwidth limits.
+pub const THROTTLE_DELAY: u64 = 100;
+
+pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8000";
+pub const DEFAULT_DATA_DIR: &str = ".rustycoin";
+/// The beacons of the local network are received on this address, and broadcast to the other.
+pub const DEFAULT_LAN_BIND: &str = "0.0.0.0:8334";
+pub const DEFAULT_LAN_BROADCAST: &str = "255.255.255.255:8334";
+/// Known to everyone, so it is refused: a cluster signs
+/// its beacons with a secret of its own, to keep the others out.
+pub const DEFAULT_LAN_SECRET: &str = "rustycoin";
+/// Environment variable giving the secret of the LAN discovery to the binaries.
+pub const LAN_SECRET_VAR: &str = "RUSTYCOIN_LAN_SECRET";
@@ -51,14 +51,14 @@ impl TryFrom<&[u8]> for VarUint {
     type Error = &'static str;
 
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
-        if bytes.len() == 0 {
+        if bytes.is_empty() {
             return Err("Empty slice");
         }
 
         let (first_byte, rest) = bytes.split_at(1);
         let first_byte = first_byte[0];
         match first_byte {
-            token if token == 0xFD => {
+            0xFD => {
                 if rest.len() < 2 {
                     Err("Slice not big enough")
                 } else {
@@ -67,7 +67,7 @@ impl TryFrom<&[u8]> for VarUint {
                     Ok(VarUint::Median(num))
                 }
             },
-            token if token == 0xFE => {
+            0xFE => {
                 if rest.len() < 4 {
                     Err("Slice not big enough")
                 } else {
@@ -76,7 +76,7 @@ impl TryFrom<&[u8]> for VarUint {
                     Ok(VarUint::Large(num))
                 }
             },
-            token if token == 0xFF => {
+            0xFF => {
                 if rest.len() < 8 {
                     Err("Slice not big enough")
                 } else {
@@ -17,7 +17,7 @@ pub struct Whoami {
 impl Whoami {
     pub fn new(version: u32, from: Address, services: Vec<String>) -> Self {
         let service_count = VarUint::new(services.len() as u64);
-        let services: Vec<VarStr> = services.into_iter().map(|s| VarStr::new(s)).collect();
+        let services: Vec<VarStr> = services.into_iter().map(VarStr::new).collect();
 
         Whoami {
             version,
@@ -40,6 +40,10 @@ impl TryFrom<&[u8]> for Whoami {
     type Error = &'static str;
 
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < 4 + ADDRESS_SIZE {
+            return Err("Slice is not big enough");
+        }
+
         let (version, bytes) = bytes.split_at(4);
         let version = u32::from_be_bytes(version.try_into().unwrap());
 
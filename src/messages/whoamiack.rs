@@ -0,0 +1,62 @@
+use std::convert::TryFrom;
+
+use super::address::{ADDRESS_SIZE, Address};
+use super::ByteSize;
+
+/// Acknowledgement of a whoami message.
+///
+/// Carries the address the sender sees the remote node connecting from,
+/// so that a node behind a NAT can learn its external address.
+#[derive(Debug, PartialEq)]
+pub struct WhoamiAck {
+    pub seen: Address,
+}
+
+impl WhoamiAck {
+    pub fn new(seen: Address) -> Self {
+        WhoamiAck {
+            seen,
+        }
+    }
+}
+
+impl ByteSize for WhoamiAck {
+    fn byte_size(&self) -> usize {
+        self.seen.byte_size()
+    }
+}
+
+impl TryFrom<&[u8]> for WhoamiAck {
+    type Error = &'static str;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < ADDRESS_SIZE {
+            return Err("Slice is not big enough");
+        }
+
+        let seen = Address::try_from(bytes)?;
+        Ok(WhoamiAck{seen})
+    }
+}
+
+impl From<WhoamiAck> for Vec<u8> {
+    fn from(ack: WhoamiAck) -> Self {
+        Vec::<u8>::from(ack.seen)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_whoamiack() {
+        let addr = Address::new(431, "127.0.0.1".parse().unwrap(), 10);
+        let ack = WhoamiAck::new(addr);
+        let bytes = Vec::<u8>::from(ack);
+
+        let addr = Address::new(431, "127.0.0.1".parse().unwrap(), 10);
+        assert_eq!(WhoamiAck::try_from(bytes.as_slice()), Ok(WhoamiAck::new(addr)));
+    }
+}
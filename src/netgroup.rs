@@ -0,0 +1,250 @@
+// Grouping and classification of the IP addresses by network.
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Group shared by all the local and private addresses.
+pub const LOCAL_GROUP: [u8; 1] = [0];
+
+/// From where an IP address can be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reachability {
+    Local,  // This machine: loopback and unspecified addresses.
+    Private,  // A local network: private, shared and link-local addresses.
+    Reserved,  // Not usable by a node: multicast, broadcast, documentation...
+    Routable,  // Reachable from the Internet.
+}
+
+/// Classifies an IP address.
+///
+/// IPv4-mapped IPv6 addresses are classified as the IPv4 they represent.
+pub fn reachability(ip: &IpAddr) -> Reachability {
+    match ip {
+        IpAddr::V4(ip) => reachability_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => reachability_v4(&ip),
+            None => reachability_v6(ip),
+        },
+    }
+}
+
+fn reachability_v4(ip: &Ipv4Addr) -> Reachability {
+    let octets = ip.octets();
+    if ip.is_loopback() || ip.is_unspecified() {
+        Reachability::Local
+    } else if ip.is_private() || ip.is_link_local()
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64) {  // Shared, 100.64.0.0/10.
+        Reachability::Private
+    } else if octets[0] == 0 || octets[0] >= 224  // This network, multicast and 240.0.0.0/4.
+        || ip.is_documentation()
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)  // Protocol assignments.
+        || (octets[0] == 198 && octets[1] & 0xfe == 18) {  // Benchmarking.
+        Reachability::Reserved
+    } else {
+        Reachability::Routable
+    }
+}
+
+fn reachability_v6(ip: &Ipv6Addr) -> Reachability {
+    let segments = ip.segments();
+    if ip.is_loopback() || ip.is_unspecified() {
+        Reachability::Local
+    } else if segments[0] & 0xfe00 == 0xfc00  // Unique local, fc00::/7.
+        || segments[0] & 0xffc0 == 0xfe80 {  // Link-local, fe80::/10.
+        Reachability::Private
+    } else if ip.is_multicast()
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)  // Documentation.
+        || (segments[0] == 0x100 && segments[1..4] == [0, 0, 0])  // Discard-only.
+        || segments[..6] == [0; 6] {  // Deprecated IPv4-compatible addresses.
+        Reachability::Reserved
+    } else {
+        Reachability::Routable
+    }
+}
+
+/// Network group of an IP address.
+///
+/// Addresses of a same group are likely to be controlled by the same
+/// entity: /16 for IPv4, /32 for IPv6. IPv4-mapped IPv6 addresses are
+/// grouped as the IPv4 they represent. Local and private addresses
+/// all share the local group.
+pub fn netgroup(ip: &IpAddr) -> Vec<u8> {
+    let ip = match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(*ip),
+        },
+        ip => *ip,
+    };
+
+    if matches!(reachability(&ip), Reachability::Local | Reachability::Private) {
+        return LOCAL_GROUP.to_vec();
+    }
+
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            vec![4, octets[0], octets[1]]
+        },
+        IpAddr::V6(ip) => {
+            let octets = ip.octets();
+            vec![6, octets[0], octets[1], octets[2], octets[3]]
+        },
+    }
+}
+
+/// A range of IP addresses, such as "10.0.0.0/8" or "2001:db8::/32".
+///
+/// A single address is a range of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, &'static str> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err("Prefix too long");
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    /// Whether the IP is in the range.
+    /// IPv4-mapped IPv6 addresses are matched as the IPv4 they represent.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => IpAddr::V6(*ip),
+            },
+            ip => *ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| "Invalid IP address")?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| "Invalid prefix")?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(ip: &str) -> Vec<u8> {
+        netgroup(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_ipv4_groups() {
+        assert_eq!(group("1.2.3.4"), group("1.2.200.100"));
+        assert_ne!(group("1.2.3.4"), group("1.3.3.4"));
+        assert_eq!(group("1.2.3.4"), group("::ffff:1.2.9.9"));
+    }
+
+    #[test]
+    fn test_ipv6_groups() {
+        assert_eq!(group("2001:db8:1::1"), group("2001:db8:ffff::2"));
+        assert_ne!(group("2001:db8::1"), group("2001:db9::1"));
+    }
+
+    #[test]
+    fn test_local_group() {
+        assert_eq!(group("127.0.0.1"), LOCAL_GROUP.to_vec());
+        assert_eq!(group("::1"), LOCAL_GROUP.to_vec());
+        assert_eq!(group("10.1.2.3"), LOCAL_GROUP.to_vec());
+        assert_eq!(group("192.168.1.1"), LOCAL_GROUP.to_vec());
+        assert_eq!(group("fd00::1"), LOCAL_GROUP.to_vec());
+        assert_eq!(group("fe80::1"), LOCAL_GROUP.to_vec());
+    }
+
+    fn reach(ip: &str) -> Reachability {
+        reachability(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_reachability() {
+        assert_eq!(reach("127.0.0.1"), Reachability::Local);
+        assert_eq!(reach("0.0.0.0"), Reachability::Local);
+        assert_eq!(reach("::1"), Reachability::Local);
+        assert_eq!(reach("::"), Reachability::Local);
+
+        assert_eq!(reach("10.1.2.3"), Reachability::Private);
+        assert_eq!(reach("172.16.0.1"), Reachability::Private);
+        assert_eq!(reach("192.168.1.1"), Reachability::Private);
+        assert_eq!(reach("169.254.1.1"), Reachability::Private);
+        assert_eq!(reach("100.64.0.1"), Reachability::Private);
+        assert_eq!(reach("::ffff:192.168.1.1"), Reachability::Private);
+        assert_eq!(reach("fd00::1"), Reachability::Private);
+        assert_eq!(reach("fe80::1"), Reachability::Private);
+
+        assert_eq!(reach("255.255.255.255"), Reachability::Reserved);
+        assert_eq!(reach("224.0.0.1"), Reachability::Reserved);
+        assert_eq!(reach("240.0.0.1"), Reachability::Reserved);
+        assert_eq!(reach("0.1.2.3"), Reachability::Reserved);
+        assert_eq!(reach("192.0.2.1"), Reachability::Reserved);
+        assert_eq!(reach("198.18.0.1"), Reachability::Reserved);
+        assert_eq!(reach("2001:db8::1"), Reachability::Reserved);
+        assert_eq!(reach("ff02::1"), Reachability::Reserved);
+        assert_eq!(reach("::102:304"), Reachability::Reserved);
+
+        assert_eq!(reach("1.2.3.4"), Reachability::Routable);
+        assert_eq!(reach("100.128.0.1"), Reachability::Routable);
+        assert_eq!(reach("::ffff:1.2.3.4"), Reachability::Routable);
+        assert_eq!(reach("2a01:e0a::1"), Reachability::Routable);
+    }
+
+    #[test]
+    fn test_cidr() {
+        let range: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(range.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!range.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!range.contains(&"::1".parse().unwrap()));
+        assert_eq!(range.to_string(), "10.1.0.0/16");
+
+        let single: Cidr = "2001:db8::1".parse().unwrap();
+        assert!(single.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!single.contains(&"2001:db8::2".parse().unwrap()));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"1.2.3.4".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+}
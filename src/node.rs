@@ -1,12 +1,118 @@
-use std::io::{self, Write};
+use std::io;
+use std::collections::{HashSet, VecDeque};
 use std::convert::TryFrom;
+use std::fmt;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use mio::net::TcpStream;
 
 use crate::messages::states::*;
 use crate::messages::header::Header;
 use crate::messages::whoami::Whoami;
+use crate::messages::whoamiack::WhoamiAck;
+use crate::messages::addr::Addr;
 use crate::messages::address::Address;
-use crate::messages::ByteSize;
+use crate::messages::message::{Frame, Message};
+use crate::messages::inv::{Inv, InvType, Inventory, MAX_INV_SIZE};
+use crate::inventory::RollingFilter;
+use crate::random::Rng;
+use crate::bandwidth::RateLimit;
+use crate::config::{EncryptionPolicy, ListenPolicy, ServerConfig};
+use crate::noise::{self, NoiseSession, StaticKey, NOISE_MARKER};
+use crate::socks5::Socks5Handshake;
+use crate::time::Clock;
+use crate::transport::Stream;
+
+/// Kind of connection with a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionType {
+    Inbound,  // The remote node engaged the connection.
+    OutboundFullRelay,  // Relays everything.
+    Manual,  // Added by the user, relays everything.
+    BlockRelayOnly,  // Never relays transactions nor addresses.
+    Feeler,  // Short-lived, tests if an address is reachable.
+}
+
+impl fmt::Display for ConnectionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ConnectionType::Inbound => "inbound",
+            ConnectionType::OutboundFullRelay => "outbound-full-relay",
+            ConnectionType::Manual => "manual",
+            ConnectionType::BlockRelayOnly => "block-relay-only",
+            ConnectionType::Feeler => "feeler",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Informations about a connected node, for display purposes.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub connection_type: ConnectionType,
+    pub established: bool,
+    pub address: Option<SocketAddr>,  // Address the node advertises.
+    pub services: Vec<String>,
+    pub latency: Option<Duration>,
+    pub connected_for: Duration,
+    pub public_key: Option<Vec<u8>>,  // Identity of the node, if the connection is encrypted.
+    pub buffered: usize,  // Bytes held in the buffers of the connection.
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}", self.addr, self.connection_type)?;
+        if !self.established {
+            write!(f, ", handshake in progress")?;
+        }
+        write!(f, ")")?;
+
+        if let Some(address) = self.address {
+            write!(f, " listening on {}", address)?;
+        }
+        if !self.services.is_empty() {
+            write!(f, " services: {}", self.services.join(","))?;
+        }
+        if let Some(latency) = self.latency {
+            write!(f, " latency: {}ms", latency.as_millis())?;
+        }
+        if let Some(key) = &self.public_key {
+            write!(f, " key: {}", noise::to_hex(key))?;
+        }
+        write!(f, " buffered: {}B", self.buffered)?;
+        write!(f, " connected for {}s", self.connected_for.as_secs())
+    }
+}
+
+/// Outgoing queue of a message, the queues are served in turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Control = 0,  // Handshake, pings and pongs.
+    Announcement = 1,  // Addresses, blocks and transactions announced.
+    Bulk = 2,  // Data sent on request, such as blocks.
+}
+
+impl Priority {
+    /// Queue of a message type.
+    pub fn of(msg_type: &str) -> Self {
+        match msg_type {
+            PING_MSG | PONG_MSG | WHOAMI_MSG | WHOAMIACK_MSG => Priority::Control,
+            ADDR_MSG | INV_MSG | STEMTX_MSG => Priority::Announcement,
+            _ => Priority::Bulk,
+        }
+    }
+}
+
+/// Encryption of a connection.
+#[derive(Debug)]
+enum Encryption {
+    Plaintext,
+    Detecting(NoiseSession),  // Waiting for the first bytes to know if the node encrypts.
+    Noise(NoiseSession),
+}
 
 /// Represents an exterior node connected to
 /// this server.
@@ -17,11 +123,32 @@ use crate::messages::ByteSize;
 ///
 /// The logic behind the protocol is also implemented here.
 #[derive(Debug)]
-pub struct Node {
-    pub connection: TcpStream,
+pub struct Node<S: Stream = TcpStream> {
+    pub connection: S,
+    pub peer_addr: SocketAddr,
     pub buffer: Vec<u8>,
-    pub is_ingoing: bool,  // True if the remote client engaged the connection, false otherwise.
+    received: VecDeque<Frame>,  // Messages read, not processed yet.
+    pub read_pending: bool,  // More data may be waiting to be read.
+    pub read_throttled: bool,  // The reading stopped at the download limit.
+    pub read_paused: bool,  // Not read from until its buffers drain.
+    write_throttled: bool,  // The writing stopped at the upload limit.
+    limits: RateLimit,  // Bandwidth allowed for this node.
+    global_limits: Arc<Mutex<RateLimit>>,  // Bandwidth allowed for all the nodes, shared with the server.
+    outgoing: [VecDeque<Vec<u8>>; 3],  // Messages waiting to be written, by priority.
+    last_queue: usize,  // Queue the last message written was taken from.
+    wire_in: Vec<u8>,  // Bytes read, not decrypted yet.
+    wire_out: Vec<u8>,  // Bytes ready to be written.
+    encryption: Encryption,
+    pub goodbye_received: bool,  // The node announced it is closing the connection.
+    goodbye_sent: bool,  // Nothing is queued after our goodbye.
+    proxy: Option<Socks5Handshake>,  // Handshake with the proxy we connect through.
+    pub connection_type: ConnectionType,
+    pub listen_policy: ListenPolicy,  // Policy of the listener that accepted the node.
+    pub policy: ListenPolicy,  // Policy applied, the whitelisted nodes get their own.
     pub is_valid: bool,  // True if the node is handling all the whoami and ping messages well.
+    pub established: bool,  // True once the whoami handshake is done.
+    config: Arc<ServerConfig>,
+    clock: Arc<dyn Clock>,
 
     current_action: CurrentAction,
 
@@ -29,22 +156,60 @@ pub struct Node {
     pub address: Option<Address>,  // Given by the whoami message
     services: Vec<String>,
 
-    last_ping_sent: u8,
-    last_ping_recv: u8,
-    last_seen: u32,
+    pub advertised: SocketAddr,  // Our own address, as we advertise it to the node.
+    pub reported_address: Option<Address>,  // Our address as seen by the node, given by the whoamiack message
+    pub remote_time: Option<u64>,  // Time of the node, given by the whoami message
+    pub received_addresses: Vec<Address>,  // Given by the whoami and addr messages
+    pub received_inventory: Vec<Inventory>,  // Announced by the node, given by the inv messages
+    pub received_stem: Vec<Inventory>,  // Transactions in their stem phase, given by the stemtx messages
+    known_inventory: RollingFilter,  // Items the node has, or we told it about.
+    pending_transactions: Vec<Inventory>,  // Waiting for the next trickle to be announced.
+    next_trickle: Instant,
+    rng: Rng,
+
+    connected_at: Instant,
+    last_ping_sent: Instant,
+    pub last_seen: Instant,
     ping_state: PingState,
+    pub latency: Option<Duration>,  // Round-trip time of the last ping.
+    pub last_useful: Option<Instant>,  // Last new inventory or addresses received.
+    pub last_block_relay: Option<Instant>,  // Last time the node relayed a new block to us.
 }
 
-impl Node {
-    /// Only needs the connection and the information
-    /// of who did the connection.
-    pub fn new(connection: TcpStream, is_ingoing: bool)
-        -> Self {
+impl<S: Stream> Node<S> {
+    /// Only needs the connection, the remote address, the information
+    /// of who did the connection, the policy of the listener,
+    /// the address we advertise, the server's config and clock.
+    pub fn new(connection: S, peer_addr: SocketAddr, connection_type: ConnectionType,
+        listen_policy: ListenPolicy, advertised: SocketAddr, config: Arc<ServerConfig>,
+        clock: Arc<dyn Clock>) -> Self {
+        let now = clock.instant();
         Node {
             connection,
+            peer_addr,
             buffer: Vec::new(),
-            is_ingoing,
+            received: VecDeque::new(),
+            read_pending: false,
+            read_throttled: false,
+            read_paused: false,
+            write_throttled: false,
+            limits: RateLimit::new(config.peer_upload_rate, config.peer_download_rate, now),
+            global_limits: Arc::new(Mutex::new(RateLimit::default())),
+            outgoing: Default::default(),
+            last_queue: Priority::Bulk as usize,
+            wire_in: Vec::new(),
+            wire_out: Vec::new(),
+            encryption: Encryption::Plaintext,
+            goodbye_received: false,
+            goodbye_sent: false,
+            proxy: None,
+            connection_type,
+            listen_policy,
+            policy: listen_policy,
             is_valid: false,
+            established: false,
+            config,
+            clock,
 
             current_action: CurrentAction::WaitingHeader,
 
@@ -52,175 +217,840 @@ impl Node {
             address: None,
             services: Vec::new(),
 
-            last_ping_sent: PING_CALLBACK,
-            last_ping_recv: PING_CALLBACK,
-            last_seen: 0,
+            advertised,
+            reported_address: None,
+            remote_time: None,
+            received_addresses: Vec::new(),
+            received_inventory: Vec::new(),
+            received_stem: Vec::new(),
+            known_inventory: RollingFilter::new(KNOWN_INVENTORY_SIZE),
+            pending_transactions: Vec::new(),
+            next_trickle: now,
+            rng: Rng::from_entropy(),
+
+            connected_at: now,
+            last_ping_sent: now,
+            last_seen: now,
             ping_state: PingState::Ack,
+            latency: None,
+            last_useful: None,
+            last_block_relay: None,
         }
     }
 
-    /// Recursively process the buffer.
-    /// The function is terminated when the node is waiting for a header
-    /// and the buffer cannot provides one.
-    pub fn handle_buffer(&mut self) {
-        self.last_seen = 0;  // Reset the variable (we got a message from the node !)
+    /// The connection goes through a SOCKS5 proxy, which has
+    /// to connect us to the node before anything else is sent.
+    pub fn through_proxy(&mut self) {
+        let handshake = Socks5Handshake::new(self.peer_addr);
+        self.wire_out.extend(handshake.start());
+        self.proxy = Some(handshake);
+    }
+
+    /// Whether the proxy has not connected us to the node yet.
+    pub fn proxy_pending(&self) -> bool {
+        self.proxy.as_ref().map(|p| !p.is_done()).unwrap_or(false)
+    }
+
+    /// Starts the encryption handshake with the node we connected to,
+    /// once the proxy is done if there is one.
+    pub fn start_encryption(&mut self, key: &StaticKey) -> io::Result<()> {
+        let mut session = NoiseSession::initiator(key)?;
+        if !self.proxy_pending() {
+            self.wire_out.extend(session.start()?);
+        }
+        self.encryption = Encryption::Noise(session);
+        Ok(())
+    }
+
+    /// Lets a node that connected to us encrypt the connection.
+    pub fn accept_encryption(&mut self, key: &StaticKey) -> io::Result<()> {
+        self.encryption = Encryption::Detecting(NoiseSession::responder(key)?);
+        Ok(())
+    }
+
+    /// Whether the encryption handshake started and is not done.
+    pub fn encryption_pending(&self) -> bool {
+        match &self.encryption {
+            Encryption::Plaintext => false,
+            Encryption::Detecting(_) => true,
+            Encryption::Noise(session) => !session.is_ready(),
+        }
+    }
+
+    /// Whether messages can not be sent yet, because of the proxy or encryption handshakes.
+    pub fn transport_pending(&self) -> bool {
+        self.proxy_pending() || self.encryption_pending()
+    }
+
+    /// Public key of the node, once the encryption handshake is done.
+    pub fn public_key(&self) -> Option<&[u8]> {
+        match &self.encryption {
+            Encryption::Noise(session) => session.remote_key(),
+            _ => None,
+        }
+    }
+
+    /// Handles bytes read from the connection: the proxy answers,
+    /// the encryption handshake and then the messages, which are
+    /// only cut out of the stream.
+    pub fn receive(&mut self, data: &[u8]) -> io::Result<()> {
+        self.wire_in.extend(data);
+
+        if let Some(handshake) = self.proxy.as_mut().filter(|p| !p.is_done()) {
+            let reply = handshake.advance(&mut self.wire_in)?;
+            self.wire_out.extend(reply);
+            if !handshake.is_done() {
+                return Ok(());
+            }
+
+            println!("Proxy connected us to {}", self.peer_addr);
+            if let Encryption::Noise(session) = &mut self.encryption {
+                self.wire_out.extend(session.start()?);
+            }
+        }
+
+        if let Encryption::Detecting(_) = self.encryption {
+            if self.wire_in.len() < NOISE_MARKER.len() {
+                return Ok(());
+            }
+
+            let detecting = mem::replace(&mut self.encryption, Encryption::Plaintext);
+            if self.wire_in.starts_with(&NOISE_MARKER) {
+                self.wire_in.drain(..NOISE_MARKER.len());
+                if let Encryption::Detecting(session) = detecting {
+                    self.encryption = Encryption::Noise(session);
+                }
+            } else if self.config.encryption == EncryptionPolicy::Required {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                    "The node does not encrypt the connection"));
+            }
+        }
+
+        match &mut self.encryption {
+            Encryption::Noise(session) => {
+                let was_ready = session.is_ready();
+                let reply = session.read(&mut self.wire_in, &mut self.buffer)?;
+                self.wire_out.extend(reply);
+                if session.is_ready() && !was_ready {
+                    println!("Encrypted connection with {}", self.peer_addr);
+                }
+            },
+            _ => self.buffer.append(&mut self.wire_in),
+        }
 
-        match self.current_action {
-            CurrentAction::WaitingHeader => if self.do_header() {return;},
-            CurrentAction::WaitingWhoami(length) => if self.do_whoami(length as usize) {return;},
+        self.handle_buffer()
+    }
+
+    /// True if the remote node engaged the connection.
+    pub fn is_ingoing(&self) -> bool {
+        self.connection_type == ConnectionType::Inbound
+    }
+
+    /// Whether addresses and transactions are exchanged with this node.
+    pub fn is_full_relay(&self) -> bool {
+        self.policy.relay && !matches!(self.connection_type, ConnectionType::BlockRelayOnly | ConnectionType::Feeler)
+    }
+
+    /// Whether the item can be announced to this node,
+    /// or taken from it. Blocks are relayed to every node.
+    pub fn relays(&self, item: &Inventory) -> bool {
+        match item.kind {
+            InvType::Block => true,
+            InvType::Transaction => self.is_full_relay(),
+        }
+    }
+
+    /// Whether the node has the item, or was told about it.
+    pub fn knows(&self, item: &Inventory) -> bool {
+        self.known_inventory.contains(item)
+    }
+
+    /// Shares the server's bandwidth limits with the node.
+    pub fn set_global_limits(&mut self, limits: Arc<Mutex<RateLimit>>) {
+        self.global_limits = limits;
+    }
+
+    /// Bytes which can be read from the node right now.
+    /// The whitelisted nodes are not limited.
+    pub fn download_allowance(&mut self, now: Instant) -> usize {
+        if self.policy.whitelisted {
+            return usize::MAX;
+        }
+        let global = self.global_limits.lock().unwrap().download_allowance(now);
+        self.limits.download_allowance(now).min(global)
+    }
+
+    /// Bytes which can be written to the node right now.
+    fn upload_allowance(&mut self, now: Instant) -> usize {
+        if self.policy.whitelisted {
+            return usize::MAX;
+        }
+        let global = self.global_limits.lock().unwrap().upload_allowance(now);
+        self.limits.upload_allowance(now).min(global)
+    }
+
+    /// Counts the bytes read against the limits.
+    pub fn downloaded(&mut self, bytes: usize) {
+        if self.policy.whitelisted {
+            return;
+        }
+        self.limits.downloaded(bytes);
+        self.global_limits.lock().unwrap().downloaded(bytes);
+    }
+
+    /// Whether reading or writing waits for the bandwidth limits.
+    pub fn throttled(&self) -> bool {
+        self.read_throttled || self.write_throttled
+    }
+
+    /// Whether historical blocks can be sent to the node: once the
+    /// daily upload cap is reached, only the outbound nodes get them.
+    pub fn serves_historical_blocks(&self, now: Instant) -> bool {
+        !self.is_ingoing() || self.policy.whitelisted
+            || !self.global_limits.lock().unwrap().cap_reached(now)
+    }
+
+    /// Seeds the random timings of the node, to replay a simulation.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Tells the node about the items it does not know yet.
+    ///
+    /// The blocks are announced right away, the transactions wait
+    /// for the next trickle, so that their origin is harder to tell.
+    pub fn announce(&mut self, items: &[Inventory]) -> io::Result<()> {
+        let mut blocks = Vec::new();
+        for item in items {
+            if !self.relays(item) || !self.known_inventory.insert(*item) {
+                continue;
+            }
+            match item.kind {
+                InvType::Block => blocks.push(*item),
+                InvType::Transaction => self.pending_transactions.push(*item),
+            }
+        }
+
+        self.send_inv(&blocks)
+    }
+
+    /// Announces a batch of the waiting transactions, at random
+    /// intervals following a Poisson process.
+    /// Inbound nodes wait longer, as a spy can open many of them.
+    fn trickle(&mut self, now: Instant) -> io::Result<()> {
+        if now < self.next_trickle {
+            return Ok(());
+        }
+        let mean = match self.is_ingoing() {
+            true => Duration::from_secs(INBOUND_TRICKLE_INTERVAL.into()),
+            false => Duration::from_secs(OUTBOUND_TRICKLE_INTERVAL.into()),
+        };
+        self.next_trickle = now + self.rng.poisson_delay(mean);
+
+        let count = self.pending_transactions.len().min(MAX_TRICKLE_BATCH);
+        let batch: Vec<Inventory> = self.pending_transactions.drain(..count).collect();
+        self.send_inv(&batch)
+    }
+
+    /// Passes a transaction in its stem phase along to the node.
+    /// The node is not told it is known, it still has to be announced.
+    pub fn send_stem(&mut self, item: Inventory) -> io::Result<()> {
+        self.send_message(STEMTX_MSG, &Vec::from(Inv::new(vec![item])))
+    }
+
+    /// Sends a block the node asked for, unless historical blocks
+    /// are not served to it anymore.
+    /// Returns false if the block was not sent.
+    pub fn send_block(&mut self, block: &[u8], now: Instant) -> io::Result<bool> {
+        if !self.serves_historical_blocks(now) {
+            println!("Upload cap reached, not sending a block to {}", self.peer_addr);
+            return Ok(false);
+        }
+        self.send_message(BLOCK_MSG, block)?;
+        Ok(true)
+    }
+
+    fn send_inv(&mut self, items: &[Inventory]) -> io::Result<()> {
+        for chunk in items.chunks(MAX_INV_SIZE as usize) {
+            self.send_message(INV_MSG, &Vec::from(Inv::new(chunk.to_vec())))?;
+        }
+        Ok(())
+    }
+
+    /// Informations about the node.
+    pub fn info(&self, now: Instant) -> PeerInfo {
+        PeerInfo {
+            addr: self.peer_addr,
+            connection_type: self.connection_type,
+            established: self.established,
+            address: self.address.as_ref().map(Address::socket_addr),
+            services: self.services.clone(),
+            latency: self.latency,
+            connected_for: now.duration_since(self.connected_at),
+            public_key: self.public_key().map(|key| key.to_vec()),
+            buffered: self.buffered(),
+        }
+    }
+
+    /// Bytes read from the node and not processed yet.
+    pub fn input_size(&self) -> usize {
+        self.wire_in.len() + self.buffer.len()
+            + self.received.iter().map(|frame| frame.payload.len()).sum::<usize>()
+    }
+
+    /// Bytes held for the node, read or waiting to be written.
+    pub fn buffered(&self) -> usize {
+        self.input_size() + self.wire_out.len()
+            + self.outgoing.iter().flatten().map(Vec::len).sum::<usize>()
+    }
+
+    /// Cuts the complete messages out of the buffer, into `received`.
+    ///
+    /// Only the headers are read here, the payloads are parsed
+    /// when the messages are processed.
+    pub fn handle_buffer(&mut self) -> io::Result<()> {
+        loop {
+            match &self.current_action {
+                CurrentAction::WaitingHeader => {
+                    let (header, buffer) = match Header::read_buffer(&self.buffer) {
+                        Some(header) => header,
+                        None => return Ok(()),  // Waiting for more data.
+                    };
+                    self.buffer = buffer;
+                    if header.magic != self.config.magic {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Wrong magic number"));
+                    }
+                    if header.length > MAX_MESSAGE_SIZE {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Message too big"));
+                    }
+
+                    self.current_action = CurrentAction::WaitingPayload(header.msg().clone(), header.length);
+                },
+                CurrentAction::WaitingPayload(msg_type, length) => {
+                    let length = *length as usize;
+                    if self.buffer.len() < length {
+                        return Ok(());  // Buffer not big enough for the moment
+                    }
+
+                    let payload = self.buffer.drain(..length).collect();
+                    self.received.push_back(Frame::new(msg_type, payload));
+                    self.current_action = CurrentAction::WaitingHeader;
+                },
+            }
         }
+    }
+
+    /// Messages cut out of the stream, to be parsed by the workers.
+    pub fn take_messages(&mut self) -> VecDeque<Frame> {
+        mem::take(&mut self.received)
+    }
 
-        self.handle_buffer();  // Continue working on the buffer (if needed).
+    /// Parses and handles the received messages, on this thread.
+    pub fn process_messages(&mut self, now: Instant) -> io::Result<()> {
+        for frame in self.take_messages() {
+            let message = Message::try_from(&frame)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.handle_message(message, now);
+        }
+        Ok(())
     }
 
     /// Check if a ping is needed to be sent.
     /// Check if we need to send a whoami message.
-    pub fn routine(&mut self) {
-        if self.last_ping_sent == 0 {
+    /// Advertise our address once the node is valid.
+    ///
+    /// Returns true if the node is considered dead.
+    pub fn routine(&mut self, now: Instant) -> bool {
+        if self.transport_pending() {
+            if now.duration_since(self.connected_at) > self.config.handshake_timeout {
+                println!("The node {} did not complete the handshake in time.", self.peer_addr);
+                return true;
+            }
+            return false;  // Nothing can be sent to the node yet.
+        }
+
+        if now.duration_since(self.last_ping_sent) >= self.config.ping_interval {
             self.send_ping(PingType::Ping).unwrap();
 
-            self.last_ping_sent = PING_CALLBACK;
+            self.last_ping_sent = now;
             self.ping_state = PingState::Sent;
         }
 
-        if !self.is_ingoing && self.whoami_state.0 == WhoamiSate::Unkn {
+        if !self.is_ingoing() && self.whoami_state.0 == WhoamiSate::Unkn {
             self.send_whoami().expect("Error while sending whoami: ");
         }
 
         self.is_valid = self.whoami_state.0 == WhoamiSate::Ack
             && self.whoami_state.1 == WhoamiSate::Ack;
 
-        if self.last_seen > LAST_SEEN_THRESHOLD {
+        if self.is_valid && !self.established {
+            self.established = true;
+            if self.connection_type == ConnectionType::Feeler {
+                // The address is reachable, we are done.
+                println!("Feeler connection to {} succeeded", self.peer_addr);
+                return true;
+            }
+
+            if self.is_full_relay() {
+                self.send_addr().expect("Error while sending addr: ");
+            }
+        }
+
+        if !self.established
+            && now.duration_since(self.connected_at) > self.config.handshake_timeout {
+            println!("The node {} did not complete the handshake in time.", self.peer_addr);
+            return true;
+        }
+
+        if self.established {
+            self.trickle(now).expect("Error while sending inv: ");
+        }
+
+        if now.duration_since(self.last_seen) > self.config.last_seen_threshold {
             // If above this threshold, we consider the node to be dead.
             println!("The node is not showing any sign of life.");
 
             // The node is not responding to our ping .. !
             self.is_valid = !(self.ping_state == PingState::Sent);
+            return !self.is_valid;
         }
-    }
 
-    /// Parse the header (if possible) and then act properly.
-    fn do_header(&mut self) -> bool {
-        if let Some((header, buffer)) = Header::read_buffer(&mut self.buffer) {
-            self.buffer = buffer;
-            if header.magic != MAGIC {
-                println!("Wrong magic number");
-                return true;
-            }
+        false
+    }
 
-            match header.msg() {
-                msg if msg == PING_MSG => {
-                    self.send_ping(PingType::Pong).unwrap();
+    /// Acts on a parsed message.
+    pub fn handle_message(&mut self, message: Message, now: Instant) {
+        match message {
+            Message::Ping => {
+                self.send_ping(PingType::Pong).unwrap();
+            },
+            Message::Pong => {
+                if self.ping_state == PingState::Sent {
+                    self.latency = Some(now.duration_since(self.last_ping_sent));
                 }
-                msg if msg == PONG_MSG => {
-                    self.ping_state = PingState::Ack;
-                },
-                msg if msg == WHOAMI_MSG => {
-                    self.current_action = CurrentAction::WaitingWhoami(header.length);
-                },
-                msg if msg == WHOAMIACK_MSG => {
-                    self.whoami_state.0 = WhoamiSate::Ack;
-                },
-                msg => println!("Header unknown: {}", msg),
-            }
-
-            false
-        } else {
-            // We are waiting for a header, so we need to back off
-            // and wait for more incoming messages.
-            true  // Escape the `handle_buffer` function.
+                self.ping_state = PingState::Ack;
+            },
+            Message::Whoami(whoami) => self.on_whoami(whoami),
+            Message::WhoamiAck(ack) => {
+                self.whoami_state.0 = WhoamiSate::Ack;
+                // The node may not tell us how it sees us.
+                if let Some(ack) = ack {
+                    self.reported_address = Some(ack.seen);
+                }
+            },
+            Message::Addr(addr) => {
+                println!("Received {} addresses", addr.addresses.len());
+                if self.is_full_relay() && !addr.addresses.is_empty() {
+                    self.received_addresses.extend(addr.addresses);
+                    self.last_useful = Some(now);
+                }
+            },
+            Message::Inv(inv) => {
+                let announced: HashSet<Inventory> = inv.items.iter().copied().collect();
+                // The node has them, no need to tell it.
+                self.pending_transactions.retain(|pending| !announced.contains(pending));
+                for item in inv.items {
+                    let new = self.known_inventory.insert(item);
+                    if self.relays(&item) {
+                        self.received_inventory.push(item);
+                        if new {
+                            self.last_useful = Some(now);
+                        }
+                    }
+                }
+            },
+            Message::StemTx(inv) => {
+                let transactions: Vec<_> = inv.items.into_iter()
+                    .filter(|item| item.kind == InvType::Transaction)
+                    .collect();
+                if self.is_full_relay() && !transactions.is_empty() {
+                    self.received_stem.extend(transactions);
+                    self.last_useful = Some(now);
+                }
+            },
+            Message::Goodbye => {
+                println!("The node {} is closing the connection.", self.peer_addr);
+                self.goodbye_received = true;
+            },
+            Message::Unknown(msg_type) => println!("Header unknown: {}", msg_type),
         }
     }
 
-    /// Parse the Whoami buffer (if big enough).
-    /// Send a whoamiack back when everyting is good.
-    /// Return true if we need to stop and wait for the buffer to be filled.
-    fn do_whoami(&mut self, length: usize) -> bool {
-        if self.buffer.len() < length {
-            return true;  // Buffer not big enough for the moment
-        }
-
-        let whoami = Whoami::try_from(self.buffer.as_slice()).expect("Error while parsing whoami: ");
-        self.buffer = self.buffer.split_at(length).1.into();
-        self.current_action = CurrentAction::WaitingHeader;
-
-        if whoami.version != VERSION {
+    /// Send a whoamiack back, and our own whoami if the node connected to us.
+    fn on_whoami(&mut self, whoami: Whoami) {
+        if whoami.version != self.config.version {
             println!("Different versions ! ({} vs {})",
-                whoami.version, VERSION);
+                whoami.version, self.config.version);
         }
         self.send_whoamiack().expect("Error while sending whoamiack: ");
 
-        if self.is_ingoing && self.whoami_state.0 == WhoamiSate::Unkn {
+        if self.is_ingoing() && self.whoami_state.0 == WhoamiSate::Unkn {
             self.send_whoami().expect("Error while sending whoami: ");
         }
 
         // Process & save infos
+        self.remote_time = Some(whoami.from.timestamp());
+        if self.is_full_relay() {
+            self.received_addresses.push(whoami.from.clone());
+        }
         self.address = Some(whoami.from.clone());
         self.services = whoami.services
             .iter()
             .map(|s| s.value())
             .collect();
+    }
 
-        false
+    /// Queue a message for the remote node, with the priority of its type.
+    pub fn send_message(&mut self, msg_type: &str, payload: &[u8]) -> io::Result<()> {
+        self.queue_message(Priority::of(msg_type), msg_type, payload)
+    }
+
+    /// Queue a message for the remote node.
+    pub fn queue_message(&mut self, priority: Priority, msg_type: &str, payload: &[u8]) -> io::Result<()> {
+        if self.goodbye_sent {
+            return Ok(());  // The connection is closing.
+        }
+        let header = Header::new(self.config.magic, msg_type, payload.len() as u64)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut message: Vec<u8> = Vec::from(header);
+        message.extend(payload);
+        self.outgoing[priority as usize].push_back(message);
+        Ok(())
+    }
+
+    /// Tell the remote node we are closing the connection.
+    /// It is the last message sent: the ones still queued are dropped,
+    /// and the ones queued later are ignored.
+    pub fn send_goodbye(&mut self) -> io::Result<()> {
+        self.outgoing.iter_mut().for_each(VecDeque::clear);
+        self.queue_message(Priority::Control, GOODBYE_MSG, &[])?;
+        self.goodbye_sent = true;
+        Ok(())
+    }
+
+    /// Takes the next message to write, from each non-empty queue in turn,
+    /// so that a big message only delays the others by itself.
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        let count = self.outgoing.len();
+        for i in 1..=count {
+            let queue = (self.last_queue + i) % count;
+            if let Some(message) = self.outgoing[queue].pop_front() {
+                self.last_queue = queue;
+                return Some(message);
+            }
+        }
+        None
+    }
+
+    /// Moves messages to the bytes ready to be written,
+    /// keeping at most about `MAX_WRITE_BUFFER` of them.
+    fn fill_wire(&mut self) -> io::Result<()> {
+        while self.wire_out.len() < MAX_WRITE_BUFFER {
+            let message = match self.next_message() {
+                Some(message) => message,
+                None => break,
+            };
+            match &mut self.encryption {
+                Encryption::Noise(session) => session.write(&message, &mut self.wire_out)?,
+                _ => self.wire_out.extend(message),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write as much of the queued data as the connection and
+    /// the upload limits accept.
+    ///
+    /// The messages are kept until the proxy and encryption handshakes are done.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let now = self.clock.instant();
+        loop {
+            if !self.transport_pending() {
+                self.fill_wire()?;
+            }
+
+            let allowance = self.upload_allowance(now);
+            let limit = self.wire_out.len().min(allowance);
+            self.write_throttled = limit < self.wire_out.len();
+
+            let mut written = 0;
+            let mut blocked = false;
+            while written < limit {
+                match self.connection.write(&self.wire_out[written..limit]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => written += n,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        blocked = true;
+                        break;
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            self.wire_out.drain(..written);
+            if written > 0 && !self.policy.whitelisted {
+                self.limits.uploaded(written, now);
+                self.global_limits.lock().unwrap().uploaded(written, now);
+            }
+
+            // Everything was written, more messages may be waiting.
+            if blocked || self.write_throttled || written == 0 || !self.wire_out.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Whether some queued data is still waiting to be written.
+    pub fn has_pending_writes(&self) -> bool {
+        !self.wire_out.is_empty()
+            || (!self.transport_pending() && self.outgoing.iter().any(|queue| !queue.is_empty()))
+    }
+
+    /// Our address, as we advertise it to the other nodes.
+    fn local_address(&self) -> Address {
+        Address::from_socket_addr(self.clock.unix(), &self.advertised)
     }
 
     /// Send a whoami message to the remote node.
     /// Sets the local `WhoamiState` to `Send`.
     fn send_whoami(&mut self) -> io::Result<()> {
-        let services = SERVICES.iter().map(|s| s.to_string()).collect();
-        let socket_addr = self.connection.local_addr()?;
-        let addr = Address::new(0, socket_addr.ip(), socket_addr.port());
-        let whoami = Whoami::new(VERSION, addr, services);
-
-        let header = Header::new(MAGIC, WHOAMI_MSG, whoami.byte_size() as u64).unwrap();
-        let header: Vec<u8> = Vec::from(header);
-        self.connection.write_all(&header)?;
+        let services = self.config.services.clone();
+        let whoami = Whoami::new(self.config.version, self.local_address(), services);
 
-        let whoami: Vec<u8> = Vec::from(whoami);
-        self.connection.write_all(&whoami)?;
+        self.send_message(WHOAMI_MSG, &Vec::from(whoami))?;
 
         self.whoami_state.0 = WhoamiSate::Sent;
         Ok(())
     }
 
     /// Send a whoamiack message to the remote node.
+    /// Tells the node which address we see it connecting from.
     /// Sets the remote `WhoamiState` to `Ack`.
     fn send_whoamiack(&mut self) -> io::Result<()> {
-        let header = Header::new(MAGIC, WHOAMIACK_MSG, 0).unwrap();
-        let header: Vec<u8> = Vec::from(header);
-        self.connection.write_all(&header)?;
+        let seen = Address::from_socket_addr(self.clock.unix(), &self.peer_addr);
+        let ack = WhoamiAck::new(seen);
+
+        self.send_message(WHOAMIACK_MSG, &Vec::from(ack))?;
 
         self.whoami_state.1 = WhoamiSate::Ack;
         Ok(())
     }
 
+    /// Advertise our own address to the remote node.
+    fn send_addr(&mut self) -> io::Result<()> {
+        let addr = Addr::new(vec![self.local_address()]);
+
+        self.send_message(ADDR_MSG, &Vec::from(addr))
+    }
+
     fn send_ping(&mut self, ping: PingType) -> io::Result<()> {
         let msg_type = if ping == PingType::Ping {
             PING_MSG
         } else {
             PONG_MSG
         };
-        let header = Header::new(MAGIC, msg_type, 0).unwrap();
-        let header: Vec<u8> = Vec::from(header);
+        self.send_message(msg_type, &[])
+    }
+}
 
-        self.connection.write_all(&header)?;
-        Ok(())
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStream;
+    use crate::server::handle_incoming_messages;
+    use crate::time::SystemClock;
+
+    fn node(connection: MemoryStream, connection_type: ConnectionType) -> Node<MemoryStream> {
+        let config = Arc::new(ServerConfig::builder().build().unwrap());
+        let peer_addr = connection.peer_addr();
+        let advertised = connection.local_addr();
+        Node::new(connection, peer_addr, connection_type, ListenPolicy::default(),
+            advertised, config, Arc::new(SystemClock))
     }
 
-    /// Actualize the timed variables.
-    pub fn delta_time(&mut self, delta: u8) {
-        self.last_ping_recv = if delta < self.last_ping_recv {
-            self.last_ping_recv - delta
-        } else {
-            0
-        };
+    /// Runs the nodes until both are done with the handshake.
+    fn handshake(alice: &mut Node<MemoryStream>, bob: &mut Node<MemoryStream>) {
+        let now = Instant::now();
+        for _ in 0..10 {
+            for node in [&mut *alice, &mut *bob] {
+                assert!(!handle_incoming_messages(node, now).unwrap());
+                node.process_messages(now).unwrap();
+                assert!(!node.routine(now));
+                node.flush().unwrap();
+            }
+        }
+        assert!(alice.established && bob.established);
+    }
 
-        self.last_ping_sent = if delta < self.last_ping_sent {
-            self.last_ping_sent - delta
-        } else {
-            0
-        };
+    #[test]
+    fn test_handshake() {
+        let (a, b) = MemoryStream::pair("10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap()).unwrap();
+        let mut alice = node(a, ConnectionType::OutboundFullRelay);
+        let mut bob = node(b, ConnectionType::Inbound);
+        handshake(&mut alice, &mut bob);
+
+        assert_eq!(bob.info(Instant::now()).address, Some("10.0.0.1:8000".parse().unwrap()));
+        assert_eq!(alice.reported_address.as_ref().map(Address::socket_addr),
+            Some("10.0.0.1:8000".parse().unwrap()));
+        assert_eq!(alice.public_key(), None);
+    }
+
+    #[test]
+    fn test_encrypted_handshake() {
+        let (a, b) = MemoryStream::pair("10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap()).unwrap();
+        let (alice_key, bob_key) = (StaticKey::generate().unwrap(), StaticKey::generate().unwrap());
+        let mut alice = node(a, ConnectionType::OutboundFullRelay);
+        let mut bob = node(b, ConnectionType::Inbound);
+        alice.start_encryption(&alice_key).unwrap();
+        bob.accept_encryption(&bob_key).unwrap();
+        handshake(&mut alice, &mut bob);
+
+        assert_eq!(alice.public_key(), Some(bob_key.public()));
+        assert_eq!(bob.info(Instant::now()).public_key, Some(alice_key.public().to_vec()));
+    }
+
+    #[test]
+    fn test_framing() {
+        let (a, b) = MemoryStream::pair("10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap()).unwrap();
+        let mut alice = node(a, ConnectionType::OutboundFullRelay);
+        let mut bob = node(b, ConnectionType::Inbound);
+
+        // More than a turn's worth of messages.
+        let count = MAX_READ_PER_TURN / 24 + 10;
+        for _ in 0..count {
+            alice.send_message(PING_MSG, &[]).unwrap();
+        }
+        alice.queue_message(Priority::Control, "getdata", &[1, 2, 3]).unwrap();
+        alice.flush().unwrap();
+
+        let now = Instant::now();
+        handle_incoming_messages(&mut bob, now).unwrap();
+        assert!(bob.read_pending);
+        let first = bob.take_messages().len();
+        assert!(first < count);
+
+        handle_incoming_messages(&mut bob, now).unwrap();
+        assert!(!bob.read_pending);
+        let messages = bob.take_messages();
+        assert_eq!(first + messages.len(), count + 1);
+        assert_eq!(messages.back(), Some(&Frame::new("getdata", vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let (a, b) = MemoryStream::pair("10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap()).unwrap();
+        let mut alice = node(a, ConnectionType::OutboundFullRelay);
+        let mut bob = node(b, ConnectionType::Inbound);
+        let now = Instant::now();
+        alice.set_global_limits(Arc::new(Mutex::new(RateLimit::new(Some(200), None, now))));
+        bob.set_global_limits(Arc::new(Mutex::new(RateLimit::new(None, Some(50), now))));
+
+        alice.send_message("getdata", &[0; 1000]).unwrap();
+        alice.flush().unwrap();
+        assert!(alice.throttled());
+        assert!(alice.has_pending_writes());
+
+        handle_incoming_messages(&mut bob, Instant::now()).unwrap();
+        assert!(bob.read_pending && bob.read_throttled);
+        assert!(bob.buffer.len() <= 50);
+        assert!(bob.take_messages().is_empty());
+    }
+
+    #[test]
+    fn test_priorities() {
+        let (a, b) = MemoryStream::pair("10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap()).unwrap();
+        let mut alice = node(a, ConnectionType::OutboundFullRelay);
+        let mut bob = node(b, ConnectionType::Inbound);
+
+        // Two big responses, then a pong and an announcement.
+        let block = vec![0; 2 * MAX_WRITE_BUFFER];
+        alice.send_message(BLOCK_MSG, &block).unwrap();
+        alice.send_message(BLOCK_MSG, &block).unwrap();
+        alice.send_message(PONG_MSG, &[]).unwrap();
+        alice.send_inv(&[Inventory::new(InvType::Block, [1; 32])]).unwrap();
+        alice.flush().unwrap();
+        assert!(!alice.has_pending_writes());
+
+        let mut types = Vec::new();
+        while types.len() < 4 {
+            handle_incoming_messages(&mut bob, Instant::now()).unwrap();
+            types.extend(bob.take_messages().into_iter().map(|frame| frame.msg_type));
+        }
+        assert_eq!(types, vec![PONG_MSG, INV_MSG, BLOCK_MSG, BLOCK_MSG]);
+    }
+
+    #[test]
+    fn test_goodbye_is_last() {
+        let (a, b) = MemoryStream::pair("10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap()).unwrap();
+        let mut alice = node(a, ConnectionType::OutboundFullRelay);
+        let mut bob = node(b, ConnectionType::Inbound);
+
+        alice.send_message(BLOCK_MSG, &[0; 100]).unwrap();
+        alice.send_goodbye().unwrap();
+        alice.send_message(PING_MSG, &[]).unwrap();
+        alice.flush().unwrap();
+
+        handle_incoming_messages(&mut bob, Instant::now()).unwrap();
+        let types: Vec<String> = bob.take_messages().into_iter().map(|frame| frame.msg_type).collect();
+        assert_eq!(types, vec![GOODBYE_MSG]);
+    }
+
+    #[test]
+    fn test_announce() {
+        let (a, b) = MemoryStream::pair("10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap()).unwrap();
+        let mut alice = node(a, ConnectionType::OutboundFullRelay);
+        let mut bob = node(b, ConnectionType::Inbound);
+        handshake(&mut alice, &mut bob);
+
+        let block = Inventory::new(InvType::Block, [1; 32]);
+        let transaction = Inventory::new(InvType::Transaction, [2; 32]);
+        bob.announce(&[block, transaction]).unwrap();
+        bob.announce(&[block, transaction]).unwrap();
+        bob.flush().unwrap();
+        assert!(bob.knows(&block) && bob.knows(&transaction));
+
+        // The block is announced once and right away, the transaction waits.
+        let now = Instant::now();
+        handle_incoming_messages(&mut alice, now).unwrap();
+        alice.process_messages(now).unwrap();
+        assert_eq!(alice.received_inventory, vec![block]);
+
+        let later = now + Duration::from_secs(INBOUND_TRICKLE_INTERVAL as u64 * 100);
+        assert!(!bob.routine(later));
+        bob.flush().unwrap();
+        handle_incoming_messages(&mut alice, later).unwrap();
+        alice.process_messages(later).unwrap();
+        assert_eq!(alice.received_inventory, vec![block, transaction]);
+    }
+
+    #[test]
+    fn test_last_useful() {
+        let (a, _b) = MemoryStream::pair("10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap()).unwrap();
+        let mut alice = node(a, ConnectionType::OutboundFullRelay);
+        let now = Instant::now();
+
+        // Pings and unknown messages do not count.
+        alice.handle_message(Message::Ping, now);
+        alice.handle_message(Message::Unknown("junk".into()), now);
+        assert_eq!(alice.last_useful, None);
+
+        let block = Inventory::new(InvType::Block, [1; 32]);
+        alice.handle_message(Message::Inv(Inv::new(vec![block])), now);
+        assert_eq!(alice.last_useful, Some(now));
 
-        self.last_seen += delta as u32;
+        // An item announced again is not new.
+        let later = now + Duration::from_secs(1);
+        alice.handle_message(Message::Inv(Inv::new(vec![block])), later);
+        assert_eq!(alice.last_useful, Some(now));
     }
 }
@@ -0,0 +1,308 @@
+// Encrypted and authenticated transport, using the Noise XX handshake.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use snow::{Builder, HandshakeState, TransportState};
+
+pub const NOISE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Sent by the initiator before the handshake, so the responder
+/// can tell an encrypted connection from a plaintext one.
+pub const NOISE_MARKER: [u8; 4] = *b"NXX1";
+/// File of the data directory holding the node's static key.
+pub const KEY_FILE: &str = "node_key";
+
+const MAX_MESSAGE: usize = 65535;
+const TAG_LENGTH: usize = 16;
+const MAX_PAYLOAD: usize = MAX_MESSAGE - TAG_LENGTH;
+
+/// Persistent key identifying the node.
+#[derive(Clone)]
+pub struct StaticKey {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl StaticKey {
+    pub fn generate() -> io::Result<Self> {
+        let keypair = builder()?.generate_keypair().map_err(noise_error)?;
+        Ok(StaticKey {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    /// Reads the key from the file, or creates the file with a new key.
+    ///
+    /// The file holds the hexadecimal private key and public key, one per line.
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let key = StaticKey::generate()?;
+                key.save(path)?;
+                return Ok(key);
+            },
+            Err(err) => return Err(err),
+        };
+
+        let mut lines = content.lines().map(from_hex);
+        match (lines.next(), lines.next()) {
+            (Some(Some(private)), Some(Some(public))) if private.len() == 32 && public.len() == 32 => {
+                Ok(StaticKey { private, public })
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid key file")),
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, format!("{}\n{}\n", to_hex(&self.private), to_hex(&self.public)))?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+}
+
+/// Only shows the public key.
+impl fmt::Debug for StaticKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StaticKey({})", to_hex(&self.public))
+    }
+}
+
+/// One side of an encrypted connection.
+///
+/// Every handshake and transport message is framed with its
+/// length, as a big endian u16.
+pub struct NoiseSession {
+    handshake: Option<HandshakeState>,
+    transport: Option<TransportState>,
+    remote_key: Option<Vec<u8>>,
+}
+
+impl NoiseSession {
+    /// The side starting the handshake.
+    pub fn initiator(key: &StaticKey) -> io::Result<Self> {
+        let handshake = builder()?
+            .local_private_key(&key.private).map_err(noise_error)?
+            .build_initiator().map_err(noise_error)?;
+        Ok(NoiseSession::with_handshake(handshake))
+    }
+
+    /// The side answering the handshake.
+    pub fn responder(key: &StaticKey) -> io::Result<Self> {
+        let handshake = builder()?
+            .local_private_key(&key.private).map_err(noise_error)?
+            .build_responder().map_err(noise_error)?;
+        Ok(NoiseSession::with_handshake(handshake))
+    }
+
+    fn with_handshake(handshake: HandshakeState) -> Self {
+        NoiseSession {
+            handshake: Some(handshake),
+            transport: None,
+            remote_key: None,
+        }
+    }
+
+    /// First bytes the initiator sends: the marker and the first handshake message.
+    pub fn start(&mut self) -> io::Result<Vec<u8>> {
+        let mut wire = NOISE_MARKER.to_vec();
+        if let Some(handshake) = self.handshake.as_mut() {
+            let mut message = vec![0; MAX_MESSAGE];
+            let length = handshake.write_message(&[], &mut message).map_err(noise_error)?;
+            push_frame(&mut wire, &message[..length]);
+        }
+        Ok(wire)
+    }
+
+    /// Whether the handshake is done.
+    pub fn is_ready(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    /// Public key of the remote node, known once the handshake is done.
+    pub fn remote_key(&self) -> Option<&[u8]> {
+        self.remote_key.as_deref()
+    }
+
+    /// Reads the complete frames of `wire`, removing them.
+    /// The decrypted data is appended to `plaintext`.
+    ///
+    /// Returns the bytes to send to the remote node.
+    pub fn read(&mut self, wire: &mut Vec<u8>, plaintext: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut reply = Vec::new();
+        let mut payload = vec![0; MAX_MESSAGE];
+        while wire.len() >= 2 {
+            let length = u16::from_be_bytes([wire[0], wire[1]]) as usize;
+            if wire.len() < 2 + length {
+                break;
+            }
+            let frame: Vec<u8> = wire.drain(..2 + length).skip(2).collect();
+
+            if let Some(transport) = self.transport.as_mut() {
+                let length = transport.read_message(&frame, &mut payload).map_err(noise_error)?;
+                plaintext.extend(&payload[..length]);
+            } else if let Some(handshake) = self.handshake.as_mut() {
+                handshake.read_message(&frame, &mut payload).map_err(noise_error)?;
+                if !handshake.is_handshake_finished() && handshake.is_my_turn() {
+                    let length = handshake.write_message(&[], &mut payload).map_err(noise_error)?;
+                    push_frame(&mut reply, &payload[..length]);
+                }
+                if handshake.is_handshake_finished() {
+                    self.finish_handshake()?;
+                }
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Encrypts the data into frames appended to `wire`.
+    pub fn write(&mut self, plaintext: &[u8], wire: &mut Vec<u8>) -> io::Result<()> {
+        let transport = match self.transport.as_mut() {
+            Some(transport) => transport,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "Handshake in progress")),
+        };
+
+        let mut message = vec![0; MAX_MESSAGE];
+        for chunk in plaintext.chunks(MAX_PAYLOAD) {
+            let length = transport.write_message(chunk, &mut message).map_err(noise_error)?;
+            push_frame(wire, &message[..length]);
+        }
+        Ok(())
+    }
+
+    fn finish_handshake(&mut self) -> io::Result<()> {
+        if let Some(handshake) = self.handshake.take() {
+            self.remote_key = handshake.get_remote_static().map(|key| key.to_vec());
+            self.transport = Some(handshake.into_transport_mode().map_err(noise_error)?);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NoiseSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NoiseSession")
+            .field("ready", &self.is_ready())
+            .field("remote_key", &self.remote_key.as_deref().map(to_hex))
+            .finish()
+    }
+}
+
+fn builder() -> io::Result<Builder<'static>> {
+    let params = NOISE_PATTERN.parse().map_err(noise_error)?;
+    Ok(Builder::new(params))
+}
+
+fn push_frame(wire: &mut Vec<u8>, message: &[u8]) {
+    wire.extend(&(message.len() as u16).to_be_bytes());
+    wire.extend(message);
+}
+
+fn noise_error(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Noise: {}", err))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let (alice_key, bob_key) = (StaticKey::generate().unwrap(), StaticKey::generate().unwrap());
+        let mut alice = NoiseSession::initiator(&alice_key).unwrap();
+        let mut bob = NoiseSession::responder(&bob_key).unwrap();
+
+        let mut to_bob = alice.start().unwrap();
+        assert!(to_bob.starts_with(&NOISE_MARKER));
+        to_bob.drain(..NOISE_MARKER.len());
+
+        let mut plaintext = Vec::new();
+        let mut to_alice = bob.read(&mut to_bob, &mut plaintext).unwrap();
+        let mut to_bob = alice.read(&mut to_alice, &mut plaintext).unwrap();
+        assert!(alice.is_ready());
+        assert!(bob.read(&mut to_bob, &mut plaintext).unwrap().is_empty());
+        assert!(bob.is_ready());
+        assert!(plaintext.is_empty());
+
+        assert_eq!(alice.remote_key(), Some(bob_key.public()));
+        assert_eq!(bob.remote_key(), Some(alice_key.public()));
+
+        // Big messages are split in several frames, which can arrive in pieces.
+        let message = vec![42; 100_000];
+        let mut wire = Vec::new();
+        alice.write(&message, &mut wire).unwrap();
+        let mut received = wire.split_off(10);
+        bob.read(&mut wire, &mut plaintext).unwrap();
+        assert!(plaintext.is_empty());
+        wire.append(&mut received);
+        bob.read(&mut wire, &mut plaintext).unwrap();
+        assert_eq!(plaintext, message);
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn test_tampered_message() {
+        let mut alice = NoiseSession::initiator(&StaticKey::generate().unwrap()).unwrap();
+        let mut bob = NoiseSession::responder(&StaticKey::generate().unwrap()).unwrap();
+        let mut wire = alice.start().unwrap().split_off(NOISE_MARKER.len());
+        let mut wire = bob.read(&mut wire, &mut Vec::new()).unwrap();
+        let mut wire = alice.read(&mut wire, &mut Vec::new()).unwrap();
+        bob.read(&mut wire, &mut Vec::new()).unwrap();
+
+        let mut wire = Vec::new();
+        alice.write(b"whoami", &mut wire).unwrap();
+        wire[5] ^= 1;
+        assert!(bob.read(&mut wire, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let path = std::env::temp_dir()
+            .join(format!("rustycoin-key-{}", std::process::id()))
+            .join(KEY_FILE);
+        let key = StaticKey::load_or_generate(&path).unwrap();
+        let loaded = StaticKey::load_or_generate(&path).unwrap();
+        assert_eq!(key.public(), loaded.public());
+
+        fs::write(&path, "not a key").unwrap();
+        assert!(StaticKey::load_or_generate(&path).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("0f0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,251 @@
+// Management of the outbound connections.
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::address_book::AddressBook;
+use crate::node::ConnectionType;
+
+/// Delay before retrying a peer that failed once.
+pub const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+/// The retry delay stops growing at this point.
+pub const RETRY_MAX_DELAY: Duration = Duration::from_secs(10 * 60);
+/// An address that was not added manually is forgotten after this many failures.
+pub const MAX_RETRIES: u32 = 8;
+
+#[derive(Debug)]
+struct ManualPeer {
+    connected: bool,  // A connection is established or in progress.
+    failures: u32,  // Failed attempts since the last established connection.
+    next_attempt: Instant,
+}
+
+/// Keeps the number of outbound connections at the target.
+///
+/// Peers added manually are reconnected indefinitely, with an exponential
+/// backoff. The full-relay and block-relay-only slots are filled with
+/// addresses from the address book, which keeps track of their own failures.
+#[derive(Debug)]
+pub struct OutboundManager {
+    target: usize,  // Full-relay connections.
+    block_relay_target: usize,  // Block-relay-only connections.
+    manual: HashMap<SocketAddr, ManualPeer>,
+    automatic: HashMap<SocketAddr, ConnectionType>,  // Automatic connections, established or in progress.
+}
+
+impl OutboundManager {
+    pub fn new(target: usize, block_relay_target: usize) -> Self {
+        OutboundManager {
+            target,
+            block_relay_target,
+            manual: HashMap::new(),
+            automatic: HashMap::new(),
+        }
+    }
+
+    /// Add an address we always want to be connected to.
+    pub fn add_manual(&mut self, addr: SocketAddr, now: Instant) {
+        let connected = self.automatic.remove(&addr).is_some();
+        self.manual.entry(addr).or_insert(ManualPeer {
+            connected,
+            failures: 0,
+            next_attempt: now,
+        });
+    }
+
+    pub fn is_manual(&self, addr: &SocketAddr) -> bool {
+        self.manual.contains_key(addr)
+    }
+
+    /// A connection to this address has been engaged.
+    /// Feelers are not managed here.
+    pub fn attempt(&mut self, addr: SocketAddr, connection_type: ConnectionType) {
+        match self.manual.get_mut(&addr) {
+            Some(peer) => peer.connected = true,
+            None => {
+                if connection_type != ConnectionType::Feeler {
+                    self.automatic.insert(addr, connection_type);
+                }
+            },
+        }
+    }
+
+    /// The connection to this address failed or has been closed.
+    /// `established` tells if the connection went through the handshake.
+    pub fn disconnected(&mut self, addr: &SocketAddr, established: bool, now: Instant) {
+        self.automatic.remove(addr);
+        if let Some(peer) = self.manual.get_mut(addr) {
+            if established {
+                peer.failures = 0;
+            } else {
+                peer.failures += 1;
+            }
+            peer.connected = false;
+            peer.next_attempt = now + backoff(peer.failures);
+        }
+    }
+
+    /// Addresses to connect to now, with the type of connection.
+    ///
+    /// Every manual peer is returned when its retry time has come. The
+    /// best addresses of the book, for which `filter` is true, fill the
+    /// missing full-relay slots, then the block-relay-only ones. They are
+    /// taken outside of the network groups we are already connected to.
+    pub fn to_connect<F>(&self, now: Instant, book: &AddressBook, unix_now: u64,
+        used_groups: &HashSet<Vec<u8>>, filter: F) -> Vec<(SocketAddr, ConnectionType)>
+        where F: Fn(&SocketAddr) -> bool {
+        let mut addresses: Vec<(SocketAddr, ConnectionType)> = self.manual.iter()
+            .filter(|(_, p)| !p.connected && p.next_attempt <= now)
+            .map(|(addr, _)| (*addr, ConnectionType::Manual))
+            .collect();
+        addresses.sort_by_key(|(addr, _)| *addr);
+
+        let missing_full = self.target.saturating_sub(self.count(ConnectionType::OutboundFullRelay));
+        let missing_block = self.block_relay_target
+            .saturating_sub(self.count(ConnectionType::BlockRelayOnly));
+        let missing = missing_full + missing_block;
+        if missing > 0 {
+            let selected = book.select(missing, unix_now, used_groups, |addr| {
+                !self.manual.contains_key(addr)
+                    && !self.automatic.contains_key(addr)
+                    && filter(addr)
+            });
+
+            addresses.extend(selected.into_iter().enumerate().map(|(i, addr)| {
+                if i < missing_full {
+                    (addr, ConnectionType::OutboundFullRelay)
+                } else {
+                    (addr, ConnectionType::BlockRelayOnly)
+                }
+            }));
+        }
+
+        addresses
+    }
+
+    /// Number of automatic connections of the given type.
+    fn count(&self, connection_type: ConnectionType) -> usize {
+        self.automatic.values().filter(|t| **t == connection_type).count()
+    }
+}
+
+/// Delay before the next attempt, after `failures` failed attempts.
+pub fn backoff(failures: u32) -> Duration {
+    if failures == 0 {
+        return RETRY_BASE_DELAY;
+    }
+
+    let factor = 2u32.saturating_pow(failures - 1);
+    RETRY_BASE_DELAY.checked_mul(factor)
+        .map(|delay| delay.min(RETRY_MAX_DELAY))
+        .unwrap_or(RETRY_MAX_DELAY)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new("10.0.0.1".parse().unwrap(), port)
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), RETRY_BASE_DELAY);
+        assert_eq!(backoff(2), RETRY_BASE_DELAY * 2);
+        assert_eq!(backoff(3), RETRY_BASE_DELAY * 4);
+        assert_eq!(backoff(100), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn test_target() {
+        let now = Instant::now();
+        let mut book = AddressBook::new();
+        for port in 1..=3 {
+            book.add(addr(port), 0, None);
+        }
+
+        let mut manager = OutboundManager::new(2, 0);
+        assert_eq!(manager.to_connect(now, &book, 0, &HashSet::new(), |_| true).len(), 2);
+
+        manager.attempt(addr(1), ConnectionType::OutboundFullRelay);
+        assert_eq!(manager.to_connect(now, &book, 0, &HashSet::new(), |_| true).len(), 1);
+
+        manager.attempt(addr(2), ConnectionType::OutboundFullRelay);
+        assert!(manager.to_connect(now, &book, 0, &HashSet::new(), |_| true).is_empty());
+
+        // A peer drops, it is replaced.
+        manager.disconnected(&addr(1), true, now);
+        book.failure(&addr(1));
+        assert_eq!(manager.to_connect(now, &book, 0, &HashSet::new(), |_| true),
+            vec![(addr(3), ConnectionType::OutboundFullRelay)]);
+    }
+
+    #[test]
+    fn test_manual_retry_with_backoff() {
+        let now = Instant::now();
+        let book = AddressBook::new();
+        let mut manager = OutboundManager::new(0, 0);
+        manager.add_manual(addr(1), now);
+        assert_eq!(manager.to_connect(now, &book, 0, &HashSet::new(), |_| true),
+            vec![(addr(1), ConnectionType::Manual)]);
+
+        manager.attempt(addr(1), ConnectionType::Manual);
+        manager.disconnected(&addr(1), false, now);
+        assert!(manager.to_connect(now, &book, 0, &HashSet::new(), |_| true).is_empty());
+        assert_eq!(manager.to_connect(now + RETRY_BASE_DELAY, &book, 0, &HashSet::new(), |_| true),
+            vec![(addr(1), ConnectionType::Manual)]);
+
+        manager.attempt(addr(1), ConnectionType::Manual);
+        manager.disconnected(&addr(1), false, now);
+        assert!(manager.to_connect(now + RETRY_BASE_DELAY, &book, 0, &HashSet::new(), |_| true).is_empty());
+
+        // Never given up.
+        for _ in 0..=MAX_RETRIES {
+            manager.attempt(addr(1), ConnectionType::Manual);
+            manager.disconnected(&addr(1), false, now);
+        }
+        let later = now + RETRY_MAX_DELAY;
+        assert_eq!(manager.to_connect(later, &book, 0, &HashSet::new(), |_| true),
+            vec![(addr(1), ConnectionType::Manual)]);
+
+        // An established connection resets the backoff.
+        manager.attempt(addr(1), ConnectionType::Manual);
+        manager.disconnected(&addr(1), true, now);
+        assert_eq!(manager.to_connect(now + RETRY_BASE_DELAY, &book, 0, &HashSet::new(), |_| true),
+            vec![(addr(1), ConnectionType::Manual)]);
+    }
+
+    #[test]
+    fn test_block_relay_slots() {
+        let now = Instant::now();
+        let mut book = AddressBook::new();
+        for port in 1..=3 {
+            book.add(addr(port), 0, None);
+        }
+
+        let mut manager = OutboundManager::new(1, 1);
+        let to_connect = manager.to_connect(now, &book, 0, &HashSet::new(), |_| true);
+        assert_eq!(to_connect.iter().map(|(_, t)| *t).collect::<Vec<_>>(),
+            vec![ConnectionType::OutboundFullRelay, ConnectionType::BlockRelayOnly]);
+
+        manager.attempt(to_connect[1].0, ConnectionType::BlockRelayOnly);
+        manager.attempt(addr(3), ConnectionType::Feeler);
+        let to_connect = manager.to_connect(now, &book, 0, &HashSet::new(), |_| true);
+        assert_eq!(to_connect.len(), 1);
+        assert_eq!(to_connect[0].1, ConnectionType::OutboundFullRelay);
+    }
+
+    #[test]
+    fn test_manual_peers_not_taken_from_book() {
+        let now = Instant::now();
+        let mut book = AddressBook::new();
+        book.add(addr(1), 0, None);
+
+        let mut manager = OutboundManager::new(1, 0);
+        manager.add_manual(addr(1), now);
+        manager.attempt(addr(1), ConnectionType::Manual);
+        assert!(manager.to_connect(now, &book, 0, &HashSet::new(), |_| true).is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+// Small random number generator, reproducible from a seed.
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+/// Seeded random number generator (xorshift64*).
+///
+/// Not suitable for cryptography, only for timings and choices
+/// an observer should not be able to predict from outside.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.max(1))  // The state can not be 0.
+    }
+
+    /// Seeded by the process' random hashing keys.
+    pub fn from_entropy() -> Self {
+        Rng::new(RandomState::new().hash_one(0u64))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Time until the next event of a Poisson process
+    /// with this mean interval.
+    pub fn poisson_delay(&mut self, mean: Duration) -> Duration {
+        let delay = -(1.0 - self.next_f64()).ln() * mean.as_secs_f64();
+        Duration::from_secs_f64(delay.min(mean.as_secs_f64() * 100.0))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng() {
+        let (mut a, mut b) = (Rng::new(42), Rng::new(42));
+        for _ in 0..100 {
+            let x = a.next_f64();
+            assert_eq!(x, b.next_f64());
+            assert!((0.0..1.0).contains(&x));
+        }
+        assert_ne!(Rng::new(1).next_u64(), Rng::new(2).next_u64());
+    }
+
+    #[test]
+    fn test_poisson_delay() {
+        let mut rng = Rng::new(7);
+        let mean = Duration::from_secs(5);
+        let delays: Vec<Duration> = (0..2000).map(|_| rng.poisson_delay(mean)).collect();
+        let average = delays.iter().sum::<Duration>() / delays.len() as u32;
+        assert!(average > Duration::from_secs(4) && average < Duration::from_secs(6));
+        assert!(delays.iter().any(|d| *d < Duration::from_secs(1)));
+    }
+}
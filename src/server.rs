@@ -1,124 +1,1006 @@
 // Contain all server's oriented functions.
-use std::collections::HashMap;
-use std::io::{self, Read};
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use mio::net::{TcpListener, TcpStream};
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Token, Waker};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_mio::v0_7::Signals;
 
-use crate::node::Node;
+use crate::node::{ConnectionType, Node, PeerInfo};
+use crate::config::{EncryptionPolicy, ListenPolicy, ProxyScope, ServerConfig};
+use crate::control::{Command, ServerHandle};
+use crate::discovery::ExternalAddress;
+use crate::outbound::OutboundManager;
+use crate::address_book::{AddressBook, ADDRESS_BOOK_FILE};
+use crate::eviction::{self, EvictionCandidate};
+use crate::noise::{StaticKey, KEY_FILE};
+use crate::netgroup::{netgroup, reachability, Cidr, Reachability};
+use crate::time::{Clock, NetworkTime, SystemClock};
+use crate::transport::{Network, Stream, TcpNetwork};
+use crate::messages::message::{Frame, Message};
+use crate::messages::states::*;
+use crate::messages::inv::{InvType, Inventory};
+use crate::inventory::RollingFilter;
+use crate::random::Rng;
+use crate::bandwidth::RateLimit;
+use crate::lan::LanDiscovery;
+use crate::dandelion::StemPool;
+use crate::workers::WorkerPool;
+use crate::slab::Slab;
+
+/// A socket the server accepts connections on.
+struct Listener<L> {
+    socket: L,
+    token: Token,
+    policy: ListenPolicy,
+}
 
 /// Representation of the server.
 ///
-/// A connected node is registered into the HashMap<Token, Node>.
-#[derive(Debug)]
-pub struct Server {
+/// A connected node is registered into the `Slab<Node>`, its token
+/// is only valid as long as the node is connected.
+/// The listeners use the first tokens, one each.
+///
+/// Nodes are reached through the network `N`, TCP by default.
+pub struct Server<N: Network = TcpNetwork> {
+    config: Arc<ServerConfig>,
+    network: N,
     poll: Poll,
-    listener: TcpListener,
-    connections: HashMap<Token, Node>,
-    server_token: Token,
-    unique_token: Token,
+    listeners: Vec<Listener<N::Listener>>,
+    connections: Slab<Node<N::Stream>>,
+    external: ExternalAddress,
+    network_time: NetworkTime,
+    outbound: OutboundManager,
+    address_book: AddressBook,
+    last_book_save: Instant,
+    last_feeler: Instant,
+    waker: Arc<Waker>,
+    commands: Receiver<Command>,
+    command_sender: Sender<Command>,
+    signals: Option<Signals>,
+    running: bool,
+    clock: Arc<dyn Clock>,
+    key: Option<StaticKey>,  // Only loaded when the encryption is enabled.
+    plaintext_peers: HashSet<SocketAddr>,  // Nodes that failed the encryption handshake.
+    inventory: RollingFilter,  // Blocks and transactions we have.
+    rejected: RollingFilter,  // Recently rejected items, not relayed.
+    stem_pool: StemPool,  // Transactions in their stem phase, not announced.
+    stem_peer: Option<Token>,  // Next node of our stem path.
+    rng: Rng,  // Seeds the random timings of the nodes.
+    limits: Arc<Mutex<RateLimit>>,  // Bandwidth allowed for all the nodes.
+    lan: Option<LanDiscovery>,  // Finds the nodes of the local network.
+    whitelist: Vec<Cidr>,  // Taken from the config, edited through the `ServerHandle`.
+    blacklist: Vec<Cidr>,
+    workers: Option<WorkerPool<Frame, Result<Message, &'static str>>>,  // None to process on this thread.
 }
 
+/// The address book is saved at this interval.
+pub const ADDRESS_BOOK_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Maximum time spent writing the pending data when shutting down.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Token of the waker used by the `ServerHandle`s.
+const WAKER_TOKEN: Token = Token(usize::MAX);
+/// Token of the SIGINT/SIGTERM signals.
+const SIGNAL_TOKEN: Token = Token(usize::MAX - 1);
+/// Token of the socket receiving the beacons of the local network.
+const LAN_TOKEN: Token = Token(usize::MAX - 2);
+
 impl Server {
-    /// Creates a new server.
+    /// Creates a new server, using TCP.
     ///
-    /// The server is hosted on the given address.
-    pub fn new(addr: &str) -> io::Result<Self> {
-        // Unique token for each incoming connection.
-        let server_token = Token(0);
-        let unique_token = Token(server_token.0 + 1);
+    /// The server listens on every address given by the config.
+    pub fn new(config: ServerConfig) -> io::Result<Self> {
+        Server::with_network(config, TcpNetwork)
+    }
+}
 
+impl<N: Network> Server<N> {
+    /// Creates a new server, reaching the nodes through the given network.
+    pub fn with_network(config: ServerConfig, network: N) -> io::Result<Self> {
         // Create a poll instance.
         let poll = Poll::new()?;
 
-        let mut listener = TcpListener::bind(addr.parse().unwrap())?;
+        let mut listeners = Vec::new();
+        for (index, listen) in config.listeners.iter().enumerate() {
+            let token = Token(index);
+            let mut socket = network.bind(listen.addr)?;
+
+            // Register the listener with poll we can receive events for it.
+            poll.registry()
+                .register(&mut socket, token, Interest::READABLE)?;
+            listeners.push(Listener {
+                socket,
+                token,
+                policy: listen.policy,
+            });
+        }
+
+        let address_book = AddressBook::load(&config.data_dir.join(ADDRESS_BOOK_FILE))?;
+
+        let key = match config.encryption {
+            EncryptionPolicy::Disabled => None,
+            _ => Some(StaticKey::load_or_generate(&config.data_dir.join(KEY_FILE))?),
+        };
+
+        // Other threads control the server through this channel.
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        let (command_sender, commands) = mpsc::channel();
 
-        // Register the server with poll we can receive events for it.
-        poll.registry()
-            .register(&mut listener, server_token, Interest::READABLE)?;
+        // The messages are parsed and validated by the workers,
+        // which wake the poll up once done.
+        let workers = match config.worker_threads {
+            0 => None,
+            threads => Some(WorkerPool::new(threads, Arc::clone(&waker), parse_message)),
+        };
 
-        // Map of `Token` -> `TcpStream`.
-        let connections = HashMap::<Token, Node>::new();
+        let mut limits = RateLimit::new(config.upload_rate, config.download_rate, Instant::now());
+        limits.set_daily_cap(config.daily_upload_cap);
+
+        let mut rng = Rng::from_entropy();
+        let lan = match &config.lan_discovery {
+            Some(lan_config) => {
+                let mut lan = LanDiscovery::bind(lan_config, config.magic, rng.next_u64(), Instant::now())?;
+                lan.register(poll.registry(), LAN_TOKEN)?;
+                Some(lan)
+            },
+            None => None,
+        };
+
+        // The connections get the tokens of their slot.
+        let connections = Slab::new();
 
         Ok(Server {
+            network,
             poll,
-            listener,
+            listeners,
             connections,
-            server_token,
-            unique_token,
+            external: ExternalAddress::new(),
+            network_time: NetworkTime::new(),
+            outbound: OutboundManager::new(config.target_outbound, config.block_relay_only),
+            address_book,
+            last_book_save: Instant::now(),
+            last_feeler: Instant::now(),
+            waker,
+            commands,
+            command_sender,
+            signals: None,
+            running: false,
+            clock: Arc::new(SystemClock),
+            key,
+            plaintext_peers: HashSet::new(),
+            inventory: RollingFilter::new(KNOWN_INVENTORY_SIZE),
+            rejected: RollingFilter::new(REJECTED_INVENTORY_SIZE),
+            stem_pool: StemPool::new(),
+            stem_peer: None,
+            rng,
+            limits: Arc::new(Mutex::new(limits)),
+            lan,
+            whitelist: config.whitelist.clone(),
+            blacklist: config.blacklist.clone(),
+            workers,
+            config: Arc::new(config),
         })
     }
 
+    /// Handle to control the server from other threads.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.command_sender.clone(), Arc::clone(&self.waker))
+    }
+
     /// Launch the main loop of the server.
     ///
-    /// Permanently listens for new connections, and calls `handle_connection_event`
+    /// Listens for new connections, and calls `handle_incoming_messages`
     /// if an event concerns an already connected node.
+    /// Returns once the server is asked to shut down.
     pub fn launch(&mut self) -> io::Result<()> {
+        if self.config.handle_signals {
+            let mut signals = Signals::new([SIGINT, SIGTERM])?;
+            self.poll.registry()
+                .register(&mut signals, SIGNAL_TOKEN, Interest::READABLE)?;
+            self.signals = Some(signals);
+        }
+
+        for listener in self.listeners.iter() {
+            println!("Server launched on {}", self.network.local_addr(&listener.socket)?);
+        }
+        self.running = true;
+
+        // Main loop
+        while self.running {
+            self.step(Some(self.config.waiting_time))?;
+        }
+
+        self.shutdown()
+    }
+
+    /// One turn of the main loop: waits at most `timeout` for events,
+    /// handles them, then does the routines.
+    ///
+    /// The nodes are always visited in the same order, so that
+    /// a simulated network behaves the same way on every run.
+    pub fn step(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        // Do not wait if some data is left to read, nor for long
+        // if some has to wait for the bandwidth limits.
+        let throttle_delay = Duration::from_millis(THROTTLE_DELAY);
+        let timeout = if self.connections.values().any(|n| n.read_pending && !n.read_throttled) {
+            Some(Duration::from_secs(0))
+        } else if self.connections.values().any(|n| n.throttled()) {
+            Some(timeout.map_or(throttle_delay, |t| t.min(throttle_delay)))
+        } else {
+            timeout
+        };
+
         // Create storage for events.
         let mut events = Events::with_capacity(128);
-        const WAITING_TIME: u8 = 5;
+        let mut served = HashSet::new();
+        match self.poll.poll(&mut events, timeout) {
+            Ok(()) => (),
+            Err(ref err) if interrupted(err) => (),  // A signal is pending.
+            Err(err) => return Err(err),
+        }
 
-        println!("Server launched on {}", self.listener.local_addr().unwrap());
+        for event in events.iter() {
+            match event.token() {
+                token if self.listeners.iter().any(|l| l.token == token) => {
+                    self.new_connection(token)?
+                },
+                WAKER_TOKEN => self.handle_commands(),
+                SIGNAL_TOKEN => self.handle_signals(),
+                LAN_TOKEN => {
+                    if let Err(err) = self.handle_beacons() {
+                        println!("Could not receive the beacons: {}", err);
+                    }
+                },
+                token => {
+                    served.insert(token);
+                    self.handle_node(token);
+                }
+            }
+        }
 
-        // Main loop
-        loop {
-            self.poll.poll(&mut events, Some(Duration::from_secs(WAITING_TIME.into())))?;
-
-            for event in events.iter() {
-                match event.token() {
-                    token if token == self.server_token => self.new_connection()?,
-                    token => {
-                        let done = if let Some(node) = self.connections.get_mut(&token) {
-                            // The event concerns an already connected node.
-                            match handle_incoming_messages(node) {
-                                Ok(result) => result,
-                                Err(err) => {
-                                    println!("Closing the connection: {}", err);
-                                    true  // Close the connection.
-                                }
-                            }
-                        } else {
-                            // Sporadic events happen, we can safely ignore them.
-                            false
-                        };
-
-                        if done {
-                            self.connections.remove(&token); // Disconnect
+        // The nodes with data left to read, which were not served yet.
+        let pending: Vec<Token> = self.connections.iter()
+            .filter(|(token, n)| n.read_pending && !served.contains(token))
+            .map(|(token, _)| token)
+            .collect();
+        for token in pending {
+            self.handle_node(token);
+        }
+        self.handle_processed();
+        // End of events handling.
+
+        // We now scan all nodes and do the routines.
+        let advertised = self.advertised_address()?;
+        let now = self.clock.instant();
+        let mut dead_nodes = Vec::new();
+        for token in self.connections.tokens() {
+            let node = match self.connections.get_mut(token) {
+                Some(node) => node,
+                None => continue,
+            };
+            node.advertised = advertised;
+            let was_established = node.established;
+            if node.routine(now) {
+                dead_nodes.push(token);
+            } else if let Err(err) = node.flush() {
+                println!("Closing the connection: {}", err);
+                dead_nodes.push(token);
+            }
+
+            if node.established && !was_established && !node.is_ingoing() {
+                self.address_book.success(&node.peer_addr, self.clock.unix());
+            }
+        }
+
+        for token in dead_nodes {
+            self.disconnect(token);
+        }
+
+        if let Some(lan) = self.lan.as_mut() {
+            if let Err(err) = lan.routine(now, self.clock.unix(), advertised.port()) {
+                println!("Could not send our beacon: {}", err);
+            }
+        }
+
+        // Transactions stuck in their stem phase are broadcast.
+        for item in self.stem_pool.expired(now) {
+            self.relay_inventory(item, None);
+        }
+
+        // Replace the lost outbound connections.
+        self.maintain_outbound(now);
+        self.start_feeler(now);
+
+        if now.duration_since(self.last_book_save) >= ADDRESS_BOOK_SAVE_INTERVAL {
+            if let Err(err) = self.save_address_book() {
+                println!("Could not save the address book: {}", err);
+            }
+            self.last_book_save = now;
+        }
+        Ok(())
+    }
+
+    /// Reads from a node and hands the messages to the workers,
+    /// or processes them right away if there are none.
+    fn handle_node(&mut self, token: Token) {
+        let now = self.clock.instant();
+        let workers = self.workers.as_ref();
+        let done = if let Some(node) = self.connections.get_mut(token) {
+            // The event concerns an already connected node,
+            // which is not read from while its buffers are full.
+            let read = match node.read_paused {
+                true => Ok(false),
+                false => handle_incoming_messages(node, now),
+            };
+            let result = read
+                .and_then(|done| match workers {
+                    Some(workers) => {
+                        for frame in node.take_messages() {
+                            workers.submit(token, frame);
                         }
-                    }
+                        Ok(done)
+                    },
+                    None => node.process_messages(now).map(|_| done || node.goodbye_received),
+                })
+                .and_then(|done| node.flush().map(|_| done));
+            match result {
+                Ok(result) => result,
+                Err(err) => {
+                    println!("Closing the connection: {}", err);
+                    true  // Close the connection.
+                }
+            }
+        } else {
+            // Sporadic events happen, we can safely ignore them.
+            false
+        };
+
+        self.collect_node_infos(token);
+        if done {
+            self.disconnect(token);
+        } else {
+            self.apply_backpressure(token);
+        }
+    }
+
+    /// Stops reading from a node once its input or its messages
+    /// waiting for the workers pass the high-water marks,
+    /// and resumes once both are below the low-water marks.
+    fn apply_backpressure(&mut self, token: Token) {
+        let processing = self.workers.as_ref().map_or(0, |workers| workers.pending(token));
+        let node = match self.connections.get_mut(token) {
+            Some(node) => node,
+            None => return,
+        };
+        let input = node.input_size();
+
+        let result = if !node.read_paused
+            && (input >= INPUT_HIGH_WATER || processing >= PROCESSING_HIGH_WATER) {
+            println!("Pausing the reads from {}, {} bytes and {} messages waiting",
+                node.peer_addr, input, processing);
+            node.read_paused = true;
+            node.read_pending = false;
+            self.poll.registry().reregister(&mut node.connection, token, Interest::WRITABLE)
+        } else if node.read_paused
+            && input <= INPUT_LOW_WATER && processing <= PROCESSING_LOW_WATER {
+            node.read_paused = false;
+            node.read_pending = true;  // Data may have come in the meantime.
+            self.poll.registry()
+                .reregister(&mut node.connection, token, Interest::READABLE.add(Interest::WRITABLE))
+        } else {
+            Ok(())
+        };
+
+        if let Err(err) = result {
+            println!("Closing the connection: {}", err);
+            self.disconnect(token);
+        }
+    }
+
+    /// Memory held for the buffers of all the nodes, in bytes.
+    pub fn buffered(&self) -> usize {
+        self.connections.values().map(Node::buffered).sum()
+    }
+
+    /// Acts on the messages the workers are done with.
+    fn handle_processed(&mut self) {
+        let mut processed = Vec::new();
+        if let Some(workers) = self.workers.as_ref() {
+            while let Some(result) = workers.try_recv() {
+                processed.push(result);
+            }
+        }
+
+        let now = self.clock.instant();
+        for (token, result) in processed {
+            let node = match self.connections.get_mut(token) {
+                Some(node) => node,
+                None => continue,  // Disconnected in the meantime.
+            };
+            let result = result
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                .and_then(|message| {
+                    node.handle_message(message, now);
+                    node.flush()
+                });
+            let done = match result {
+                Ok(()) => node.goodbye_received,
+                Err(err) => {
+                    println!("Closing the connection: {}", err);
+                    true
                 }
+            };
+
+            self.collect_node_infos(token);
+            if done {
+                self.disconnect(token);
+            } else {
+                self.apply_backpressure(token);
+            }
+        }
+    }
+
+    /// Replaces the clock of the server, by a mock one in tests.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let now = clock.instant();
+        self.last_book_save = now;
+        self.last_feeler = now;
+        self.clock = clock;
+    }
+
+    /// Seeds the random timings of the nodes, to replay a simulation.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Replaces the address book, before the server is launched.
+    pub fn set_address_book(&mut self, address_book: AddressBook) {
+        self.address_book = address_book;
+    }
+
+    /// Processes the commands sent through the `ServerHandle`s.
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Connect(addr) => self.add_peer(addr),
+                Command::Disconnect(addr) => {
+                    let now = self.clock.instant();
+                    let tokens: Vec<Token> = self.connections.iter()
+                        .filter(|(_, n)| n.peer_addr == addr || n.info(now).address == Some(addr))
+                        .map(|(token, _)| token)
+                        .collect();
+                    for token in tokens {
+                        if let Some(node) = self.connections.get_mut(token) {
+                            let _ = node.send_goodbye().and_then(|_| node.flush());
+                        }
+                        self.disconnect(token);
+                    }
+                },
+                Command::Broadcast(msg_type, payload) => {
+                    for node in self.connections.values_mut().filter(|n| n.established) {
+                        if let Err(err) = node.send_message(&msg_type, &payload) {
+                            println!("Could not broadcast {}: {}", msg_type, err);
+                            break;
+                        }
+                    }
+                },
+                Command::Relay(item) => {
+                    self.submit_inventory(item);
+                },
+                Command::Reject(item) => self.reject_inventory(item),
+                Command::Whitelist(range) => self.whitelist(range),
+                Command::Unwhitelist(range) => self.unwhitelist(range),
+                Command::Blacklist(range) => self.blacklist(range),
+                Command::Unblacklist(range) => self.unblacklist(range),
+                Command::Shutdown => self.running = false,
+            }
+        }
+    }
+
+    /// Adds the nodes found on the local network to the address book.
+    fn handle_beacons(&mut self) -> io::Result<()> {
+        let port = self.advertised_address()?.port();
+        let unix = self.clock.unix();
+        let found = match self.lan.as_mut() {
+            Some(lan) => lan.receive(unix, port)?,
+            None => return Ok(()),
+        };
+
+        for addr in found {
+            if self.is_blacklisted(&addr.ip()) {
+                continue;
+            }
+            if self.address_book.get(&addr).is_none() {
+                println!("Found {} on the local network", addr);
+            }
+            self.address_book.add(addr, unix, None);
+        }
+        Ok(())
+    }
+
+    /// Exempts the nodes of a range from the limits,
+    /// the ones connected included.
+    pub fn whitelist(&mut self, range: Cidr) {
+        if !self.whitelist.contains(&range) {
+            self.whitelist.push(range);
+        }
+        self.update_policies();
+    }
+
+    pub fn unwhitelist(&mut self, range: Cidr) {
+        self.whitelist.retain(|r| *r != range);
+        self.update_policies();
+    }
+
+    /// Applies the whitelist to the connected nodes.
+    fn update_policies(&mut self) {
+        let policies: Vec<(Token, ListenPolicy)> = self.connections.iter()
+            .map(|(token, n)| (token, self.policy_for(&n.peer_addr.ip(), n.listen_policy)))
+            .collect();
+        for (token, policy) in policies {
+            if let Some(node) = self.connections.get_mut(token) {
+                node.policy = policy;
+            }
+        }
+    }
+
+    /// Refuses the nodes of a range, and disconnects the ones connected.
+    pub fn blacklist(&mut self, range: Cidr) {
+        if !self.blacklist.contains(&range) {
+            self.blacklist.push(range);
+        }
+
+        let tokens: Vec<Token> = self.connections.iter()
+            .filter(|(_, n)| range.contains(&n.peer_addr.ip()))
+            .map(|(token, _)| token)
+            .collect();
+        for token in tokens {
+            if let Some(node) = self.connections.get(token) {
+                println!("Disconnecting {} (blacklisted)", node.peer_addr);
+            }
+            self.disconnect(token);
+        }
+    }
+
+    pub fn unblacklist(&mut self, range: Cidr) {
+        self.blacklist.retain(|r| *r != range);
+    }
+
+    pub fn is_whitelisted(&self, ip: &IpAddr) -> bool {
+        self.whitelist.iter().any(|range| range.contains(ip))
+    }
+
+    pub fn is_blacklisted(&self, ip: &IpAddr) -> bool {
+        self.blacklist.iter().any(|range| range.contains(ip))
+    }
+
+    /// Policy applied to a node: the whitelisted ones always relay.
+    fn policy_for(&self, ip: &IpAddr, policy: ListenPolicy) -> ListenPolicy {
+        match self.is_whitelisted(ip) {
+            true => ListenPolicy { relay: true, whitelisted: true },
+            false => policy,
+        }
+    }
+
+    /// Address the beacons of the local network are received on.
+    pub fn lan_addr(&self) -> Option<SocketAddr> {
+        self.lan.as_ref().and_then(|lan| lan.local_addr().ok())
+    }
+
+    /// Stops the server when SIGINT or SIGTERM is received.
+    fn handle_signals(&mut self) {
+        if let Some(signals) = self.signals.as_mut() {
+            for signal in signals.pending() {
+                println!("Received signal {}", signal);
+                self.running = false;
             }
-            // End of events handling.
+        }
+    }
 
-            // We now scan all nodes and do the routines.
-            for node in self.connections.values_mut() {
-                node.delta_time(WAITING_TIME);
-                node.routine();
+    /// Says goodbye to every node, writes what can be written
+    /// in `SHUTDOWN_TIMEOUT` and saves the address book.
+    fn shutdown(&mut self) -> io::Result<()> {
+        println!("Shutting down the server...");
+        for node in self.connections.values_mut() {
+            let _ = node.send_goodbye();
+        }
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        let mut events = Events::with_capacity(128);
+        loop {
+            self.connections.retain(|_, node| node.flush().is_ok());
+            if self.connections.values().all(|n| !n.has_pending_writes())
+                || Instant::now() >= deadline {
+                break;
+            }
+            match self.poll.poll(&mut events, Some(Duration::from_millis(100))) {
+                Ok(()) => (),
+                Err(ref err) if interrupted(err) => (),
+                Err(err) => return Err(err),
             }
         }
+        self.connections.clear();
+
+        if let Some(mut signals) = self.signals.take() {
+            self.poll.registry().deregister(&mut signals)?;
+        }
+
+        self.save_address_book()?;
+        println!("Server stopped");
+        Ok(())
+    }
+
+    /// Adds a node the server always tries to be connected to.
+    /// The connection is re-established whenever it drops.
+    pub fn add_peer(&mut self, addr: SocketAddr) {
+        self.address_book.add(addr, 0, None);
+        self.outbound.add_manual(addr, self.clock.instant());
+        self.maintain_outbound(self.clock.instant());
     }
 
     /// Connects the server to a specified node.
     /// Registers the node.
-    #[allow(dead_code)]
-    pub fn connect(&mut self, addr: SocketAddr) -> io::Result<()> {
-        let connection = TcpStream::connect(addr)?;
-        println!("Connected to {}", connection.peer_addr().unwrap());
+    pub fn connect(&mut self, addr: SocketAddr, connection_type: ConnectionType)
+            -> io::Result<()> {
+        if connection_type == ConnectionType::Inbound {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "An outbound connection can not be inbound"));
+        }
+
+        if self.is_blacklisted(&addr.ip()) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "The address is blacklisted"));
+        }
+
+        let policy = self.policy_for(&addr.ip(), ListenPolicy::default());
+        if !policy.whitelisted && self.outbound_count() >= self.config.max_outbound {
+            return Err(io::Error::other("Too many outbound connections"));
+        }
+
+        self.outbound.attempt(addr, connection_type);
+        self.address_book.attempt(&addr, self.clock.unix());
+        let proxy = self.proxy_for(&addr);
+        let connection = match self.network.connect(proxy.unwrap_or(addr)) {
+            Ok(connection) => connection,
+            Err(err) => {
+                self.outbound.disconnected(&addr, false, self.clock.instant());
+                self.address_book.failure(&addr);
+                return Err(err);
+            }
+        };
+        match proxy {
+            Some(proxy) => println!("Connecting to {} through {} ({})", addr, proxy, connection_type),
+            None => println!("Connecting to {} ({})", addr, connection_type),
+        }
 
         // Now register the node
-        self.register_node(connection, false)?;
+        let token = self.register_node(connection, addr, connection_type, ListenPolicy::default())?;
+        let encrypt = match self.config.encryption {
+            EncryptionPolicy::Disabled => false,
+            EncryptionPolicy::Preferred => !self.plaintext_peers.contains(&addr),
+            EncryptionPolicy::Required => true,
+        };
+        if let Some(node) = self.connections.get_mut(token) {
+            if proxy.is_some() {
+                node.through_proxy();
+            }
+            if let (true, Some(key)) = (encrypt, self.key.as_ref()) {
+                node.start_encryption(key)?;
+            }
+        }
         Ok(())
     }
 
+    /// The proxy to connect to this address through, if any.
+    fn proxy_for(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        let proxy = self.config.proxy.as_ref()?;
+        match proxy.scope {
+            ProxyScope::All => Some(proxy.addr),
+            ProxyScope::Routable if reachability(&addr.ip()) == Reachability::Routable => {
+                Some(proxy.addr)
+            },
+            ProxyScope::Routable => None,
+        }
+    }
+
+    /// Starts a feeler connection, if it is time to.
+    ///
+    /// A feeler connects to an address we never connected to,
+    /// and disconnects once the whoami handshake is done.
+    fn start_feeler(&mut self, now: Instant) {
+        let interval = self.config.feeler_interval;
+        if interval == Duration::from_secs(0) || now.duration_since(self.last_feeler) < interval {
+            return;
+        }
+        self.last_feeler = now;
+
+        if self.connections.values().any(|n| n.connection_type == ConnectionType::Feeler) {
+            return;  // The previous one is not done yet.
+        }
+
+        let own_addresses = self.own_addresses();
+        let addr = self.address_book.select_feeler(self.clock.unix(), |addr| {
+            !own_addresses.contains(addr) && !self.is_connected_to(addr) && !self.is_blacklisted(&addr.ip())
+        });
+
+        if let Some(addr) = addr {
+            if let Err(err) = self.connect(addr, ConnectionType::Feeler) {
+                println!("Could not connect to {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// Dials the addresses given by the outbound manager.
+    fn maintain_outbound(&mut self, now: Instant) {
+        let own_addresses = self.own_addresses();
+        let used_groups: HashSet<Vec<u8>> = self.connections.values()
+            .filter(|n| !n.is_ingoing() && n.connection_type != ConnectionType::Feeler)
+            .map(|n| netgroup(&n.peer_addr.ip()))
+            .collect();
+        let candidates = self.outbound.to_connect(now, &self.address_book, self.clock.unix(),
+            &used_groups, |addr| {
+            !own_addresses.contains(addr) && !self.is_connected_to(addr) && !self.is_blacklisted(&addr.ip())
+        });
+
+        for (addr, connection_type) in candidates {
+            if self.outbound_count() >= self.config.max_outbound {
+                break;
+            }
+
+            if self.is_connected_to(&addr) {
+                continue;  // The node already connected to us.
+            }
+
+            if let Err(err) = self.connect(addr, connection_type) {
+                println!("Could not connect to {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// Closes the connection with a node.
+    fn disconnect(&mut self, token: Token) {
+        if let Some(workers) = self.workers.as_ref() {
+            workers.remove(token);
+        }
+        if let Some(node) = self.connections.remove(token) {
+            self.external.forget(&node.peer_addr);
+            self.network_time.remove_sample(&node.peer_addr);
+            if !node.is_ingoing() {
+                if node.encryption_pending() && !node.proxy_pending()
+                    && self.config.encryption == EncryptionPolicy::Preferred {
+                    // The node may not support encryption.
+                    println!("Falling back to plaintext with {}", node.peer_addr);
+                    self.plaintext_peers.insert(node.peer_addr);
+                }
+                self.outbound.disconnected(&node.peer_addr, node.established, self.clock.instant());
+                if !node.established {
+                    self.address_book.failure(&node.peer_addr);
+                }
+            }
+        }
+    }
+
+    /// Gathers what the node told us since the last time.
+    fn collect_node_infos(&mut self, token: Token) {
+        let node = match self.connections.get_mut(token) {
+            Some(node) => node,
+            None => return,
+        };
+        let peer = node.peer_addr;
 
-    /// Accepts and register a new connection.
-    fn new_connection(&mut self) -> io::Result<()> {
+        // The node may have told us how it sees us.
+        if let Some(reported) = node.reported_address.take() {
+            self.external.report(peer, reported.ip());
+        }
+
+        // And what time it is.
+        if let Some(remote_time) = node.remote_time.take() {
+            self.network_time.add_sample(peer, remote_time, self.clock.unix());
+        }
+
+        // New addresses we could connect to.
+        // Only nodes on our side of the Internet may tell us about local ones.
+        let now = self.clock.unix();
+        let local_source = reachability(&peer.ip()) != Reachability::Routable;
+        for address in node.received_addresses.drain(..) {
+            let usable = address.is_routable() || (local_source && address.port() != 0
+                && matches!(address.reachability(), Reachability::Local | Reachability::Private));
+            if usable {
+                let last_seen = address.timestamp().min(now);
+                self.address_book.add(address.socket_addr(), last_seen, Some(peer));
+            }
+        }
+
+        // Blocks and transactions to pass on. Until they are exchanged,
+        // an item announced by a node counts as received from it.
+        let items: Vec<Inventory> = node.received_inventory.drain(..).collect();
+        let stem: Vec<Inventory> = node.received_stem.drain(..).collect();
+        for item in items {
+            if self.relay_inventory(item, Some(token)) && item.kind == InvType::Block {
+                // Protects the node from eviction.
+                let now = self.clock.instant();
+                if let Some(node) = self.connections.get_mut(token) {
+                    node.last_block_relay = Some(now);
+                }
+            }
+        }
+        for item in stem {
+            self.stem(item, Some(token));
+        }
+    }
+
+    /// Announces a new block or transaction to the established nodes
+    /// not knowing it yet, except the one it came from.
+    ///
+    /// Returns false if the item was already known or recently rejected.
+    pub fn relay_inventory(&mut self, item: Inventory, source: Option<Token>) -> bool {
+        self.stem_pool.remove(&item);  // Someone broadcast it.
+        if self.rejected.contains(&item) || !self.inventory.insert(item) {
+            return false;
+        }
+
+        for (token, node) in self.connections.iter_mut() {
+            if Some(token) == source || !node.established {
+                continue;
+            }
+            if let Err(err) = node.announce(&[item]) {
+                println!("Could not announce to {}: {}", node.peer_addr, err);
+            }
+        }
+        true
+    }
+
+    /// Relays a block or transaction of our own.
+    ///
+    /// With dandelion, a transaction first goes along the stem path.
+    pub fn submit_inventory(&mut self, item: Inventory) -> bool {
+        if item.kind == InvType::Transaction && self.config.dandelion {
+            return self.stem(item, None);
+        }
+        self.relay_inventory(item, None)
+    }
+
+    /// Passes a transaction along the stem path, or broadcasts it
+    /// with `FLUFF_PROBABILITY` when it comes from another node.
+    /// Returns false if the transaction was already known.
+    fn stem(&mut self, item: Inventory, source: Option<Token>) -> bool {
+        if !self.config.dandelion {
+            return self.relay_inventory(item, source);
+        }
+        if item.kind != InvType::Transaction || self.rejected.contains(&item)
+            || self.inventory.contains(&item) {
+            return false;
+        }
+
+        let mean = Duration::from_secs(STEM_EMBARGO.into());
+        let embargo = self.clock.instant() + self.rng.poisson_delay(mean);
+        if !self.stem_pool.insert(item, embargo) {
+            return false;  // The path loops, the embargo will end it.
+        }
+
+        let fluff = source.is_some() && self.rng.next_f64() < FLUFF_PROBABILITY;
+        let peer = if fluff { None } else { self.stem_peer(source) };
+        match peer.and_then(|token| self.connections.get_mut(token)) {
+            Some(node) => {
+                if let Err(err) = node.send_stem(item) {
+                    println!("Could not pass a transaction to {}: {}", node.peer_addr, err);
+                }
+                true
+            },
+            None => self.relay_inventory(item, source),
+        }
+    }
+
+    /// The node our stem path goes through, chosen at random among
+    /// the established full-relay outbound nodes, and kept while connected.
+    fn stem_peer(&mut self, exclude: Option<Token>) -> Option<Token> {
+        let usable = |token: Token, node: &Node<N::Stream>| {
+            Some(token) != exclude && node.established && !node.is_ingoing() && node.is_full_relay()
+                && node.connection_type != ConnectionType::Feeler
+        };
+
+        if let Some(token) = self.stem_peer {
+            match self.connections.get(token) {
+                Some(node) if usable(token, node) => return Some(token),
+                _ => (),
+            }
+        }
+
+        let candidates: Vec<Token> = self.connections.iter()
+            .filter(|(token, node)| usable(*token, node))
+            .map(|(token, _)| token)
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let token = candidates[(self.rng.next_u64() % candidates.len() as u64) as usize];
+        if exclude.is_none() {
+            self.stem_peer = Some(token);
+        }
+        Some(token)
+    }
+
+    /// Sends a block a node asked for.
+    ///
+    /// Returns false if the node is gone, or if the daily upload cap
+    /// keeps us from serving it historical blocks.
+    pub fn send_block(&mut self, token: Token, block: &[u8]) -> io::Result<bool> {
+        let now = self.clock.instant();
+        match self.connections.get_mut(token) {
+            Some(node) => node.send_block(block, now),
+            None => Ok(false),
+        }
+    }
+
+    /// Marks an item as invalid, it is not relayed anymore.
+    pub fn reject_inventory(&mut self, item: Inventory) {
+        self.rejected.insert(item);
+    }
+
+    /// Whether we have the block or transaction.
+    pub fn has_inventory(&self, item: &Inventory) -> bool {
+        self.inventory.contains(item)
+    }
+
+    /// Path of the address book file.
+    fn address_book_path(&self) -> PathBuf {
+        self.config.data_dir.join(ADDRESS_BOOK_FILE)
+    }
+
+    /// Saves the address book in the data directory.
+    pub fn save_address_book(&self) -> io::Result<()> {
+        self.address_book.save(&self.address_book_path())
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.address_book
+    }
+
+
+    /// Disconnects the least useful inbound node to make room for a new one.
+    /// Whitelisted nodes are never evicted.
+    /// Returns false if no node could be evicted.
+    fn evict_inbound(&mut self) -> bool {
+        let candidates = self.connections.iter()
+            .filter(|(_, n)| n.is_ingoing() && !n.policy.whitelisted)
+            .map(|(token, n)| EvictionCandidate {
+                token,
+                latency: n.latency,
+                last_useful: n.last_useful,
+                last_block_relay: n.last_block_relay,
+                netgroup: netgroup(&n.peer_addr.ip()),
+            })
+            .collect();
+
+        match eviction::select_node_to_evict(candidates, self.clock.instant()) {
+            Some(token) => {
+                if let Some(node) = self.connections.get(token) {
+                    println!("Evicting {} to make room for a new node", node.peer_addr);
+                }
+                self.disconnect(token);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Accepts and register the new connections of a listener.
+    fn new_connection(&mut self, token: Token) -> io::Result<()> {
+        let listener = match self.listeners.iter().position(|l| l.token == token) {
+            Some(listener) => listener,
+            None => return Ok(()),
+        };
+        let listen_policy = self.listeners[listener].policy;
         loop {
             // Received an event for the TCP server socket, which
             // indicates we can accept an connection.
-            let (connection, address) = match self.listener.accept() {
+            let (connection, address) = match self.network.accept(&self.listeners[listener].socket) {
                 Ok((connection, address)) => (connection, address),
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                     // If we get a `WouldBlock` error we know our
@@ -134,54 +1016,162 @@ impl Server {
                 }
             };
 
+            let address = unmapped(address);
+            if self.is_blacklisted(&address.ip()) {
+                println!("Refused connection from: {} (blacklisted)", address);
+                continue;
+            }
+
+            let policy = self.policy_for(&address.ip(), listen_policy);
+            if !policy.whitelisted && self.ip_count(&address) >= self.config.max_per_ip {
+                println!("Refused connection from: {} (too many connections from this IP)", address);
+                continue;  // The connection is closed when dropped.
+            }
+
+            if !policy.whitelisted && self.inbound_count() >= self.config.max_inbound
+                && !self.evict_inbound() {
+                println!("Refused connection from: {} (too many connections)", address);
+                continue;
+            }
+
             println!("Accepted connection from: {}", address);
-            self.register_node(connection, true)?;
+            let token = self.register_node(connection, address, ConnectionType::Inbound, listen_policy)?;
+            if let (Some(node), Some(key)) = (self.connections.get_mut(token), self.key.as_ref()) {
+                node.accept_encryption(key)?;
+            }
         }
 
         Ok(())
     }
 
-    /// Add a node to the HashMap.
-    /// Register the node in the poll for future events.
-    fn register_node(&mut self, mut connection: TcpStream, is_ingoing: bool)
-            -> io::Result<()> {
-        let token = self.next_token();
+    /// Inserts the node in the slab of connections,
+    /// and registers it in the poll for future events.
+    /// Returns its generation-checked token.
+    fn register_node(&mut self, mut connection: N::Stream, peer_addr: SocketAddr,
+            connection_type: ConnectionType, listen_policy: ListenPolicy) -> io::Result<Token> {
+        let token = self.connections.vacant_token();
         self.poll.registry()
             .register(&mut connection, token, Interest::READABLE.add(Interest::WRITABLE))?;
 
-        let node = Node::new(connection, is_ingoing);
-        self.connections.insert(token, node);
-        Ok(())
+        let mut node = Node::new(connection, peer_addr, connection_type, listen_policy,
+            self.advertised_address()?, Arc::clone(&self.config), Arc::clone(&self.clock));
+        node.policy = self.policy_for(&peer_addr.ip(), listen_policy);
+        node.set_seed(self.rng.next_u64());
+        node.set_global_limits(Arc::clone(&self.limits));
+        Ok(self.connections.insert(node))
+    }
+
+    /// The address we advertise to other nodes.
+    ///
+    /// Uses the IP most of our peers see us connecting from,
+    /// and falls back on the first address we are listening on.
+    fn advertised_address(&self) -> io::Result<SocketAddr> {
+        let listen_addr = self.local_addr()?;
+        let ip = self.external.best().unwrap_or_else(|| listen_addr.ip());
+        Ok(SocketAddr::new(ip, listen_addr.port()))
+    }
+
+    /// Public key identifying the server, if the encryption is enabled.
+    pub fn public_key(&self) -> Option<&[u8]> {
+        self.key.as_ref().map(StaticKey::public)
     }
 
-    /// Creates a unique token.
-    fn next_token(&mut self) -> Token {
-        let next = self.unique_token.0;
-        self.unique_token.0 += 1;
-        Token(next)
+    /// Network-adjusted time, in seconds since the Unix epoch.
+    ///
+    /// This is the time to use when validating block timestamps.
+    pub fn network_time(&self) -> u64 {
+        self.network_time.adjusted(self.clock.unix())
     }
 
-    pub fn get_valid_nodes<'a>(&'a self) -> Vec<&'a Node> {
+    pub fn get_valid_nodes(&self) -> Vec<&Node<N::Stream>> {
         self.connections.values()
             .filter(|n| n.is_valid)
             .collect()
     }
+
+    /// Informations about every connected node.
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        let now = self.clock.instant();
+        self.connections.values()
+            .map(|n| n.info(now))
+            .collect()
+    }
+
+    /// First address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.network.local_addr(&self.listeners[0].socket)
+    }
+
+    /// Every address the server is listening on.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter()
+            .map(|l| self.network.local_addr(&l.socket))
+            .collect()
+    }
+
+    /// Addresses we must not connect to, as they lead back to us.
+    fn own_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = self.local_addrs().unwrap_or_default();
+        addresses.extend(self.advertised_address().ok());
+        addresses
+    }
+
+    /// Whether we are connected to the node listening on this address.
+    fn is_connected_to(&self, addr: &SocketAddr) -> bool {
+        self.connections.values().any(|n| {
+            n.peer_addr == *addr
+                || n.address.as_ref()
+                    .map(|a| a.socket_addr() == *addr)
+                    .unwrap_or(false)
+        })
+    }
+
+    /// Number of inbound connections with this IP.
+    fn ip_count(&self, addr: &SocketAddr) -> usize {
+        self.connections.values()
+            .filter(|n| n.is_ingoing() && n.peer_addr.ip() == addr.ip())
+            .count()
+    }
+
+    fn inbound_count(&self) -> usize {
+        self.connections.values().filter(|n| n.is_ingoing()).count()
+    }
+
+    fn outbound_count(&self) -> usize {
+        self.connections.values().filter(|n| !n.is_ingoing()).count()
+    }
 }
 
 /// Read and store the incoming messages into the
 /// node's buffer.
 ///
 /// Calls the handling buffer's node function.
-pub fn handle_incoming_messages(
-    node: &mut Node,
+/// At most `MAX_READ_PER_TURN` bytes are read, so the other nodes
+/// are not kept waiting, `read_pending` tells if some may be left.
+/// Less is read if the download limits do not allow it,
+/// then `read_throttled` is set.
+pub fn handle_incoming_messages<S: Stream>(
+    node: &mut Node<S>,
+    now: Instant,
 ) -> io::Result<bool> {
     let mut connection_closed = false;
     let mut received_data = vec![0; 4096];
     let mut bytes_read = 0;
+    let allowance = node.download_allowance(now);
+    let limit = allowance.min(MAX_READ_PER_TURN);
+    node.read_pending = false;
+    node.read_throttled = false;
 
     // We can (maybe) read from the connection.
     loop {
-        match node.connection.read(&mut received_data[bytes_read..]) {
+        if bytes_read >= limit {
+            node.read_pending = true;
+            node.read_throttled = allowance < MAX_READ_PER_TURN;
+            break;
+        }
+
+        let end = received_data.len().min(limit);
+        match node.connection.read(&mut received_data[bytes_read..end]) {
             Ok(0) => {
                 // Reading 0 bytes means the other side has closed the
                 // connection or is done writing, then so are we.
@@ -204,20 +1194,37 @@ pub fn handle_incoming_messages(
     }
 
     if bytes_read != 0 {
+        node.last_seen = now;  // We got a message from the node !
+        node.downloaded(bytes_read);
         let received_data = &received_data[..bytes_read];
-        node.buffer.extend(received_data);
-        node.handle_buffer();
+        node.receive(received_data)?;
     }
 
-    if connection_closed {
-        println!("Connection with node {} closed.",
-            node.connection.peer_addr().unwrap());
+    if connection_closed || node.goodbye_received {
+        println!("Connection with node {} closed.", node.peer_addr);
         return Ok(true);
     }
 
     Ok(false)
 }
 
+/// Job of the workers.
+fn parse_message(frame: Frame) -> Result<Message, &'static str> {
+    Message::try_from(&frame)
+}
+
+/// IPv4 peers accepted on a dual-stack IPv6 socket show up
+/// with an IPv4-mapped address, gives back the IPv4 one.
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
 fn would_block(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::WouldBlock
 }
@@ -225,3 +1232,476 @@ fn would_block(err: &io::Error) -> bool {
 fn interrupted(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::Interrupted
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryNetwork;
+    use crate::messages::header::Header;
+    use crate::messages::states::WHOAMI_MSG;
+    use std::time::Duration;
+
+    #[test]
+    fn test_several_servers() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-several-servers-{}", std::process::id()));
+        let fast = ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .data_dir(data_dir.join("fast"))
+            .ping_interval(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let slow = ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .data_dir(data_dir.join("slow"))
+            .magic(42)
+            .build()
+            .unwrap();
+
+        let fast = Server::new(fast).unwrap();
+        let slow = Server::new(slow).unwrap();
+        assert_ne!(fast.local_addr().unwrap(), slow.local_addr().unwrap());
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-shutdown-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .data_dir(&data_dir)
+            .build()
+            .unwrap();
+        let mut server = Server::new(config).unwrap();
+        let handle = server.handle();
+
+        let thread = std::thread::spawn(move || server.launch());
+        handle.connect("127.0.0.1:1".parse().unwrap()).unwrap();
+        handle.shutdown().unwrap();
+
+        thread.join().unwrap().unwrap();
+        assert!(data_dir.join(ADDRESS_BOOK_FILE).exists());
+        assert!(handle.shutdown().is_err());
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_several_listeners() {
+        let whitelisted = ListenPolicy { relay: false, whitelisted: true };
+        let config = ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .add_listener("127.0.0.1:0", whitelisted)
+            .max_per_ip(1)
+            .build();
+        // The same address can not be given twice.
+        assert!(config.is_err());
+
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-listeners-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .add_listener("[::]:0", whitelisted)
+            .data_dir(&data_dir)
+            .max_per_ip(1)
+            .build()
+            .unwrap();
+        let mut server = Server::new(config).unwrap();
+        let addrs = server.local_addrs().unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(server.listeners[1].token, Token(1));
+
+        // An IPv4 node connecting to the dual-stack listener.
+        let port = addrs[1].port();
+        let _first = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let _second = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        server.new_connection(Token(1)).unwrap();
+
+        // Whitelisted nodes are not limited per IP.
+        assert_eq!(server.inbound_count(), 2);
+        for node in server.connections.values() {
+            assert_eq!(node.peer_addr.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+            assert_eq!(node.policy, whitelisted);
+            assert!(!node.is_full_relay());
+        }
+
+        // The limit applies on the other listener.
+        let _third = std::net::TcpStream::connect(addrs[0]).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        server.new_connection(Token(0)).unwrap();
+        assert_eq!(server.inbound_count(), 2);
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    /// Accepts one connection as a SOCKS5 proxy would, and returns
+    /// the requested target and the first message sent to it.
+    fn socks5_stand_in(listener: std::net::TcpListener) -> (Vec<u8>, Header) {
+        use std::io::{Read, Write};
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        stream.write_all(&[5, 0]).unwrap();
+
+        let mut request = [0; 10];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 1]).unwrap();
+
+        let mut header = vec![0; 24];
+        stream.read_exact(&mut header).unwrap();
+        let (header, _) = Header::read_buffer(&header).unwrap();
+        (request.to_vec(), header)
+    }
+
+    #[test]
+    fn test_socks5_proxy() {
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap().to_string();
+        let stand_in = std::thread::spawn(move || socks5_stand_in(proxy));
+
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-proxy-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .data_dir(&data_dir)
+            .proxy(&proxy_addr, ProxyScope::All)
+            .waiting_time(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let mut server = Server::new(config).unwrap();
+        let handle = server.handle();
+        let thread = std::thread::spawn(move || server.launch());
+
+        handle.connect("1.2.3.4:8000".parse().unwrap()).unwrap();
+        let (request, header) = stand_in.join().unwrap();
+        assert_eq!(request, vec![5, 1, 0, 1, 1, 2, 3, 4, 0x1f, 0x40]);
+        assert_eq!(header.msg(), WHOAMI_MSG);
+
+        handle.shutdown().unwrap();
+        thread.join().unwrap().unwrap();
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_proxy_scope() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-proxy-scope-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .data_dir(&data_dir)
+            .proxy("127.0.0.1:9050", ProxyScope::Routable)
+            .build()
+            .unwrap();
+        let server = Server::new(config).unwrap();
+        let proxy = Some("127.0.0.1:9050".parse().unwrap());
+        assert_eq!(server.proxy_for(&"1.2.3.4:8000".parse().unwrap()), proxy);
+        assert_eq!(server.proxy_for(&"192.168.1.2:8000".parse().unwrap()), None);
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn test_memory_network() {
+        let network = MemoryNetwork::new();
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-memory-{}", std::process::id()));
+        let config = |listen: &str, dir: &str| ServerConfig::builder()
+            .listen(listen)
+            .data_dir(data_dir.join(dir))
+            .waiting_time(Duration::from_millis(10))
+            .build()
+            .unwrap();
+
+        let mut alice = Server::with_network(config("10.0.0.1:8000", "alice"),
+            network.host("10.0.0.1".parse().unwrap())).unwrap();
+        let mut bob = Server::with_network(config("10.0.0.2:8000", "bob"),
+            network.host("10.0.0.2".parse().unwrap())).unwrap();
+        let (alice_handle, bob_handle) = (alice.handle(), bob.handle());
+
+        let alice_thread = std::thread::spawn(move || alice.launch().map(|_| alice));
+        let bob_thread = std::thread::spawn(move || bob.launch().map(|_| bob));
+        bob_handle.connect("10.0.0.1:8000".parse().unwrap()).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        bob_handle.shutdown().unwrap();
+        alice_handle.shutdown().unwrap();
+
+        let bob = bob_thread.join().unwrap().unwrap();
+        let alice = alice_thread.join().unwrap().unwrap();
+        // The whoami handshake was done: Bob tried Alice's address,
+        // and Alice learned Bob's one.
+        let alice_info = bob.address_book().get(&"10.0.0.1:8000".parse().unwrap()).unwrap();
+        assert!(alice_info.tried);
+        assert!(alice.address_book().get(&"10.0.0.2:8000".parse().unwrap()).is_some());
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_backpressure() {
+        use std::io::Write;
+
+        let network = MemoryNetwork::new();
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-backpressure-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("10.0.0.1:8000")
+            .data_dir(&data_dir)
+            .worker_threads(0)
+            .build()
+            .unwrap();
+        let mut server = Server::with_network(config, network.host("10.0.0.1".parse().unwrap())).unwrap();
+
+        // A message too big to ever fit under the high-water mark.
+        let mut client = network.host("10.0.0.2".parse().unwrap())
+            .connect("10.0.0.1:8000".parse().unwrap()).unwrap();
+        let header = Header::new(MAGIC, "getdata", 16 * 1024 * 1024).unwrap();
+        client.write_all(&Vec::<u8>::from(header)).unwrap();
+        for _ in 0..10 {
+            server.step(Some(Duration::from_millis(1))).unwrap();
+        }
+        assert!(server.connections.is_empty());
+
+        // Legal messages read faster than they are processed.
+        let mut client = network.host("10.0.0.3".parse().unwrap())
+            .connect("10.0.0.1:8000".parse().unwrap()).unwrap();
+        let header = Vec::<u8>::from(Header::new(MAGIC, "getdata", MAX_MESSAGE_SIZE).unwrap());
+        for _ in 0..(2 * INPUT_HIGH_WATER as u64 / MAX_MESSAGE_SIZE) {
+            client.write_all(&header).unwrap();
+            client.write_all(&vec![0; MAX_MESSAGE_SIZE as usize]).unwrap();
+        }
+        server.step(Some(Duration::from_millis(1))).unwrap();
+        let token = server.connections.tokens()[0];
+        let node = server.connections.get_mut(token).unwrap();
+        while node.input_size() < INPUT_HIGH_WATER {
+            handle_incoming_messages(node, Instant::now()).unwrap();
+        }
+        server.apply_backpressure(token);
+        assert!(server.connections.get(token).unwrap().read_paused);
+
+        // Resumed once processed, as the workers would, until everything is read.
+        server.step(Some(Duration::from_millis(1))).unwrap();
+        assert!(server.connections.get(token).unwrap().read_paused);
+        server.connections.get_mut(token).unwrap().process_messages(Instant::now()).unwrap();
+        server.apply_backpressure(token);
+        assert!(!server.connections.get(token).unwrap().read_paused);
+        for _ in 0..500 {
+            server.step(Some(Duration::from_millis(1))).unwrap();
+        }
+        assert!(!server.connections.get(token).unwrap().read_paused);
+        assert_eq!(server.buffered(), 0);
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn test_lan_discovery() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-lan-{}", std::process::id()));
+        let config = |dir: &str, broadcast: &str| ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .data_dir(data_dir.join(dir))
+            .lan_discovery("127.0.0.1:0", broadcast, "lab")
+            .build()
+            .unwrap();
+
+        // Only Alice listens where the beacons are sent, Bob learns about her from her answer.
+        let mut alice = Server::new(config("alice", "127.0.0.1:9")).unwrap();
+        let alice_lan = alice.lan_addr().unwrap().to_string();
+        let mut bob = Server::new(config("bob", &alice_lan)).unwrap();
+        let (alice_addr, bob_addr) = (alice.local_addr().unwrap(), bob.local_addr().unwrap());
+
+        for _ in 0..20 {
+            alice.step(Some(Duration::from_millis(10))).unwrap();
+            bob.step(Some(Duration::from_millis(10))).unwrap();
+        }
+        assert!(alice.address_book().get(&bob_addr).is_some());
+        assert!(bob.address_book().get(&alice_addr).is_some());
+
+        // Beacons signed with another secret are ignored.
+        let mut eve = Server::new(ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .data_dir(data_dir.join("eve"))
+            .lan_discovery("127.0.0.1:0", &alice_lan, "guess")
+            .build()
+            .unwrap()).unwrap();
+        for _ in 0..10 {
+            eve.step(Some(Duration::from_millis(10))).unwrap();
+            alice.step(Some(Duration::from_millis(10))).unwrap();
+        }
+        assert!(alice.address_book().get(&eve.local_addr().unwrap()).is_none());
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn test_whitelist_and_blacklist() {
+        let network = MemoryNetwork::new();
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-lists-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("10.0.0.1:8000")
+            .data_dir(&data_dir)
+            .max_inbound(0)
+            .whitelist("10.0.0.2")
+            .blacklist("10.0.0.3/32")
+            .worker_threads(0)
+            .build()
+            .unwrap();
+        let mut server = Server::with_network(config, network.host("10.0.0.1".parse().unwrap())).unwrap();
+        let handle = server.handle();
+
+        let mut clients = Vec::new();
+        for ip in ["10.0.0.2", "10.0.0.3", "10.0.0.4"] {
+            clients.push(network.host(ip.parse().unwrap())
+                .connect("10.0.0.1:8000".parse().unwrap()).unwrap());
+            server.step(Some(Duration::from_millis(10))).unwrap();
+        }
+        // Only the whitelisted node is accepted, despite the limit.
+        let peers: Vec<SocketAddr> = server.connections.values().map(|n| n.peer_addr).collect();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ip(), "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert!(server.connections.values().all(|n| n.policy.whitelisted));
+        assert!(server.connect("10.0.0.3:8000".parse().unwrap(), ConnectionType::Manual).is_err());
+
+        // The lists apply to the connected nodes too.
+        handle.unwhitelist("10.0.0.2".parse().unwrap()).unwrap();
+        server.step(Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(server.connections.len(), 1);
+        assert!(server.connections.values().all(|n| !n.policy.whitelisted));
+        handle.whitelist("10.0.0.0/24".parse().unwrap()).unwrap();
+        server.step(Some(Duration::from_millis(10))).unwrap();
+        assert!(server.connections.values().all(|n| n.policy.whitelisted));
+
+        handle.blacklist("10.0.0.0/24".parse().unwrap()).unwrap();
+        server.step(Some(Duration::from_millis(10))).unwrap();
+        assert!(server.connections.is_empty());
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn test_block_relay_protection() {
+        let network = MemoryNetwork::new();
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-block-relay-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("10.0.0.1:8000")
+            .data_dir(&data_dir)
+            .worker_threads(0)
+            .build()
+            .unwrap();
+        let mut server = Server::with_network(config, network.host("10.0.0.1".parse().unwrap())).unwrap();
+
+        let mut clients = Vec::new();
+        for i in 0..(eviction::PROTECTED_BY_LATENCY + eviction::PROTECTED_BY_USEFULNESS + 4) {
+            clients.push(network.host(IpAddr::from([10, 0, 1, i as u8]))
+                .connect("10.0.0.1:8000".parse().unwrap()).unwrap());
+            server.step(Some(Duration::from_millis(10))).unwrap();
+        }
+
+        // One node tells us about a new block.
+        let (relayer, node) = server.connections.iter_mut().last().unwrap();
+        let block = Inventory::new(InvType::Block, [1; 32]);
+        node.received_inventory.push(block);
+        server.collect_node_infos(relayer);
+        assert!(server.connections.get(relayer).unwrap().last_block_relay.is_some());
+
+        // It is the last one we would evict.
+        while server.evict_inbound() {}
+        assert!(server.connections.get(relayer).is_some());
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn test_fluff_skips_source() {
+        let network = MemoryNetwork::new();
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-fluff-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("10.0.0.1:8000")
+            .data_dir(&data_dir)
+            .dandelion(true)
+            .worker_threads(0)
+            .build()
+            .unwrap();
+        let mut server = Server::with_network(config, network.host("10.0.0.1".parse().unwrap())).unwrap();
+
+        let mut clients = Vec::new();
+        for ip in ["10.0.0.2", "10.0.0.3"] {
+            clients.push(network.host(ip.parse().unwrap())
+                .connect("10.0.0.1:8000".parse().unwrap()).unwrap());
+            server.step(Some(Duration::from_millis(10))).unwrap();
+        }
+        let tokens: Vec<Token> = server.connections.iter_mut()
+            .map(|(token, node)| {
+                node.established = true;
+                token
+            })
+            .collect();
+
+        // Without outbound nodes there is no stem path, the transaction is fluffed.
+        let tx = Inventory::new(InvType::Transaction, [4; 32]);
+        assert!(server.stem(tx, Some(tokens[0])));
+        assert!(!server.connections.get(tokens[0]).unwrap().knows(&tx));
+        assert!(server.connections.get(tokens[1]).unwrap().knows(&tx));
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn test_daily_upload_cap() {
+        let network = MemoryNetwork::new();
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-upload-cap-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("10.0.0.1:8000")
+            .data_dir(&data_dir)
+            .daily_upload_cap(1000)
+            .worker_threads(0)
+            .build()
+            .unwrap();
+        let mut server = Server::with_network(config, network.host("10.0.0.1".parse().unwrap())).unwrap();
+        let _client = network.host("10.0.0.2".parse().unwrap())
+            .connect("10.0.0.1:8000".parse().unwrap()).unwrap();
+        server.step(Some(Duration::from_millis(10))).unwrap();
+        let token = server.connections.iter().next().unwrap().0;
+
+        // Served until the cap is reached.
+        assert!(server.send_block(token, &[0; 2000]).unwrap());
+        for _ in 0..10 {
+            server.step(Some(Duration::from_millis(1))).unwrap();
+        }
+        assert!(server.limits.lock().unwrap().uploaded_today() >= 2000);
+        assert!(!server.send_block(token, &[0; 2000]).unwrap());
+
+        server.connections.get_mut(token).unwrap().policy.whitelisted = true;
+        assert!(server.send_block(token, &[0; 2000]).unwrap());
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn test_unmapped() {
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:8000".parse().unwrap();
+        assert_eq!(unmapped(mapped), "1.2.3.4:8000".parse().unwrap());
+        let ipv6: SocketAddr = "[::1]:8000".parse().unwrap();
+        assert_eq!(unmapped(ipv6), ipv6);
+    }
+
+    #[test]
+    fn test_address_in_use() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("rustycoin-address-in-use-{}", std::process::id()));
+        let config = ServerConfig::builder()
+            .listen("127.0.0.1:0")
+            .data_dir(&data_dir)
+            .build()
+            .unwrap();
+        let server = Server::new(config).unwrap();
+
+        let addr = server.local_addr().unwrap().to_string();
+        let config = ServerConfig::builder()
+            .listen(&addr)
+            .data_dir(&data_dir)
+            .build()
+            .unwrap();
+        assert!(Server::new(config).is_err());
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+}
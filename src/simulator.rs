@@ -0,0 +1,664 @@
+// Deterministic simulation of a network of servers, in a single thread.
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mio::event::Source;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+
+use crate::address_book::AddressBook;
+use crate::config::{ServerConfig, ServerConfigBuilder};
+use crate::memory::{self, Notifier};
+use crate::random::Rng;
+use crate::server::Server;
+use crate::time::{Clock, MockClock};
+use crate::transport::{Network, Stream};
+
+/// Time taken by the data to go from a node to another.
+pub const DEFAULT_LATENCY: Duration = Duration::from_millis(50);
+/// A lost packet is sent again after this delay.
+pub const RETRANSMISSION_DELAY: Duration = Duration::from_millis(200);
+/// The simulated time moves by this step.
+pub const TICK: Duration = Duration::from_millis(10);
+/// Unix time at which every simulation starts.
+pub const SIMULATION_START: u64 = 1_600_000_000;
+/// Port every simulated node listens on.
+pub const SIMULATION_PORT: u16 = 8333;
+
+/// Data going one way.
+#[derive(Debug, Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    closed: bool,  // The writer closed its end, and everything was delivered.
+    reader_gone: bool,
+    last_arrival: Option<Instant>,  // So that the data arrives in order.
+}
+
+/// Data on its way.
+#[derive(Debug)]
+struct Packet {
+    deliver_at: Instant,
+    from: IpAddr,
+    to: IpAddr,
+    pipe: Arc<Mutex<Pipe>>,
+    notify: Arc<pipe::Sender>,
+    data: Vec<u8>,
+    close: bool,
+}
+
+/// Connections waiting to be accepted.
+#[derive(Debug)]
+struct Backlog {
+    pending: VecDeque<(SimStream, SocketAddr)>,
+    notify: pipe::Sender,
+}
+
+/// State shared by every handle on the network.
+#[derive(Debug)]
+struct Core {
+    clock: MockClock,
+    rng: Rng,
+    latency: Duration,
+    loss: f64,
+    groups: HashMap<IpAddr, usize>,  // Partition of each host, 0 if not given.
+    packets: Vec<Packet>,  // Sorted by delivery time.
+    listeners: HashMap<SocketAddr, Arc<Mutex<Backlog>>>,
+    next_port: u16,
+}
+
+impl Core {
+    fn group(&self, ip: &IpAddr) -> usize {
+        self.groups.get(ip).copied().unwrap_or(0)
+    }
+
+    /// Puts data on its way, with the latency of the link and
+    /// the retransmissions of the lost packets.
+    fn send(&mut self, from: IpAddr, to: IpAddr, pipe: &Arc<Mutex<Pipe>>,
+            notify: &Arc<pipe::Sender>, data: Vec<u8>, close: bool) {
+        let mut delay = self.latency;
+        while self.loss > 0.0 && self.rng.next_f64() < self.loss {
+            delay += RETRANSMISSION_DELAY;
+        }
+
+        let mut deliver_at = self.clock.instant() + delay;
+        {
+            let mut pipe = pipe.lock().unwrap();
+            if let Some(last_arrival) = pipe.last_arrival {
+                deliver_at = deliver_at.max(last_arrival);
+            }
+            pipe.last_arrival = Some(deliver_at);
+        }
+
+        // Keep the packets sorted, the ones sent first staying first.
+        let index = self.packets.partition_point(|p| p.deliver_at <= deliver_at);
+        self.packets.insert(index, Packet {
+            deliver_at,
+            from,
+            to,
+            pipe: Arc::clone(pipe),
+            notify: Arc::clone(notify),
+            data,
+            close,
+        });
+    }
+
+    /// Delivers the packets that arrived, except across partitions.
+    fn deliver(&mut self) {
+        let now = self.clock.instant();
+        let mut kept = Vec::new();
+        for packet in self.packets.drain(..) {
+            let partitioned = self.groups.get(&packet.from).copied().unwrap_or(0)
+                != self.groups.get(&packet.to).copied().unwrap_or(0);
+            if packet.deliver_at > now || partitioned {
+                kept.push(packet);
+                continue;
+            }
+
+            let mut pipe = packet.pipe.lock().unwrap();
+            pipe.data.extend(packet.data);
+            pipe.closed |= packet.close;
+            memory::notify(&packet.notify);
+        }
+        self.packets = kept;
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = self.next_port.checked_add(1).unwrap_or(memory::FIRST_EPHEMERAL_PORT);
+        port
+    }
+}
+
+/// One end of a simulated connection.
+#[derive(Debug)]
+pub struct SimStream {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+    readable: pipe::Receiver,
+    notify_peer: Arc<pipe::Sender>,
+    core: Arc<Mutex<Core>>,
+}
+
+impl Read for SimStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        memory::clear(&self.readable);
+
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.data.is_empty() {
+            return if incoming.closed {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            };
+        }
+
+        let n = buf.len().min(incoming.data.len());
+        for (byte, data) in buf.iter_mut().zip(incoming.data.drain(..n)) {
+            *byte = data;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SimStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.outgoing.lock().unwrap().reader_gone {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.core.lock().unwrap().send(self.local_addr.ip(), self.peer_addr.ip(),
+            &self.outgoing, &self.notify_peer, buf.to_vec(), false);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The peer sees the connection closed once the data
+/// written before arrived.
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        self.incoming.lock().unwrap().reader_gone = true;
+        if let Ok(mut core) = self.core.lock() {
+            core.send(self.local_addr.ip(), self.peer_addr.ip(),
+                &self.outgoing, &self.notify_peer, Vec::new(), true);
+        }
+    }
+}
+
+impl Source for SimStream {
+    fn register(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        self.readable.register(registry, token, Interest::READABLE)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        self.readable.reregister(registry, token, Interest::READABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.readable.deregister(registry)
+    }
+}
+
+impl Stream for SimStream {}
+
+#[derive(Debug)]
+pub struct SimListener {
+    addr: SocketAddr,
+    backlog: Arc<Mutex<Backlog>>,
+    readable: pipe::Receiver,
+    core: Arc<Mutex<Core>>,
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        if let Ok(mut core) = self.core.lock() {
+            core.listeners.remove(&self.addr);
+        }
+    }
+}
+
+impl Source for SimListener {
+    fn register(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        self.readable.register(registry, token, Interest::READABLE)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        self.readable.reregister(registry, token, Interest::READABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.readable.deregister(registry)
+    }
+}
+
+/// Handle on a simulated network, for one host.
+#[derive(Debug, Clone)]
+pub struct SimNetwork {
+    ip: IpAddr,
+    core: Arc<Mutex<Core>>,
+}
+
+impl SimNetwork {
+    pub fn new(clock: MockClock, seed: u64) -> Self {
+        SimNetwork {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            core: Arc::new(Mutex::new(Core {
+                clock,
+                rng: Rng::new(seed),
+                latency: DEFAULT_LATENCY,
+                loss: 0.0,
+                groups: HashMap::new(),
+                packets: Vec::new(),
+                listeners: HashMap::new(),
+                next_port: memory::FIRST_EPHEMERAL_PORT,
+            })),
+        }
+    }
+
+    /// Handle on the same network, for a host with this IP.
+    pub fn host(&self, ip: IpAddr) -> Self {
+        SimNetwork {
+            ip,
+            core: Arc::clone(&self.core),
+        }
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.core.lock().unwrap().latency = latency;
+    }
+
+    /// Probability for a packet to be lost, and sent again later.
+    pub fn set_loss(&self, loss: f64) {
+        self.core.lock().unwrap().loss = loss.clamp(0.0, 0.99);
+    }
+
+    /// Hosts of different groups can not reach each other.
+    /// The hosts not given are in the group 0.
+    pub fn set_groups(&self, groups: HashMap<IpAddr, usize>) {
+        self.core.lock().unwrap().groups = groups;
+    }
+
+    /// Next random number of the simulation.
+    pub fn random(&self) -> u64 {
+        self.core.lock().unwrap().rng.next_u64()
+    }
+
+    /// Delivers the data that arrived.
+    pub fn deliver(&self) {
+        self.core.lock().unwrap().deliver();
+    }
+
+    /// Amount of data on its way.
+    pub fn in_flight(&self) -> usize {
+        self.core.lock().unwrap().packets.iter().map(|p| p.data.len()).sum()
+    }
+}
+
+impl Network for SimNetwork {
+    type Stream = SimStream;
+    type Listener = SimListener;
+
+    fn bind(&self, mut addr: SocketAddr) -> io::Result<SimListener> {
+        let mut core = self.core.lock().unwrap();
+        if addr.port() == 0 {
+            addr.set_port(core.ephemeral_port());
+        }
+        if core.listeners.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+
+        let notifier = Notifier::new()?;
+        let backlog = Arc::new(Mutex::new(Backlog {
+            pending: VecDeque::new(),
+            notify: notifier.sender,
+        }));
+        core.listeners.insert(addr, Arc::clone(&backlog));
+
+        Ok(SimListener {
+            addr,
+            backlog,
+            readable: notifier.receiver,
+            core: Arc::clone(&self.core),
+        })
+    }
+
+    fn accept(&self, listener: &SimListener) -> io::Result<(SimStream, SocketAddr)> {
+        memory::clear(&listener.readable);
+        match listener.backlog.lock().unwrap().pending.pop_front() {
+            Some(connection) => Ok(connection),
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// The connection is accepted right away, the latency
+    /// only applies to the data.
+    fn connect(&self, addr: SocketAddr) -> io::Result<SimStream> {
+        let mut core = self.core.lock().unwrap();
+        if core.group(&self.ip) != core.group(&addr.ip()) {
+            return Err(io::Error::other("Network unreachable"));
+        }
+        let backlog = match core.listeners.get(&addr) {
+            Some(backlog) => Arc::clone(backlog),
+            None => return Err(io::ErrorKind::ConnectionRefused.into()),
+        };
+
+        let local_addr = SocketAddr::new(self.ip, core.ephemeral_port());
+        drop(core);
+
+        let to_remote = Arc::new(Mutex::new(Pipe::default()));
+        let to_local = Arc::new(Mutex::new(Pipe::default()));
+        let (local_notifier, remote_notifier) = (Notifier::new()?, Notifier::new()?);
+        let local = SimStream {
+            local_addr,
+            peer_addr: addr,
+            incoming: Arc::clone(&to_local),
+            outgoing: Arc::clone(&to_remote),
+            readable: local_notifier.receiver,
+            notify_peer: Arc::new(remote_notifier.sender),
+            core: Arc::clone(&self.core),
+        };
+        let remote = SimStream {
+            local_addr: addr,
+            peer_addr: local_addr,
+            incoming: to_remote,
+            outgoing: to_local,
+            readable: remote_notifier.receiver,
+            notify_peer: Arc::new(local_notifier.sender),
+            core: Arc::clone(&self.core),
+        };
+
+        let mut backlog = backlog.lock().unwrap();
+        backlog.pending.push_back((remote, local_addr));
+        memory::notify(&backlog.notify);
+        Ok(local)
+    }
+
+    fn local_addr(&self, listener: &SimListener) -> io::Result<SocketAddr> {
+        Ok(listener.addr)
+    }
+}
+
+/// Used to give each simulation its own directory.
+static SIMULATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs servers on a simulated network, with a mock clock.
+///
+/// Everything happens in the calling thread and only depends
+/// on the seed, so a run can be replayed.
+pub struct Simulator {
+    clock: MockClock,
+    network: SimNetwork,
+    nodes: Vec<Server<SimNetwork>>,
+    addresses: Vec<SocketAddr>,
+    data_dir: PathBuf,
+}
+
+impl Simulator {
+    pub fn new(seed: u64) -> Self {
+        let clock = MockClock::new(SIMULATION_START);
+        let data_dir = std::env::temp_dir().join(format!("rustycoin-simulation-{}-{}",
+            std::process::id(), SIMULATIONS.fetch_add(1, Ordering::Relaxed)));
+
+        Simulator {
+            network: SimNetwork::new(clock.clone(), seed),
+            clock,
+            nodes: Vec::new(),
+            addresses: Vec::new(),
+            data_dir,
+        }
+    }
+
+    pub fn network(&self) -> &SimNetwork {
+        &self.network
+    }
+
+    /// Adds a node with the default settings.
+    pub fn add_node(&mut self) -> io::Result<usize> {
+        self.add_node_with(ServerConfig::builder())
+    }
+
+    /// Adds a node, the listen address and data directory are set by the simulator.
+    /// The messages are processed on the simulation's thread, without workers.
+    /// Returns the index of the node.
+    pub fn add_node_with(&mut self, builder: ServerConfigBuilder) -> io::Result<usize> {
+        let index = self.nodes.len();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, (index / 256) as u8, (index % 256) as u8, 1));
+        let addr = SocketAddr::new(ip, SIMULATION_PORT);
+
+        let config = builder
+            .listen(&addr.to_string())
+            .data_dir(self.data_dir.join(index.to_string()))
+            .worker_threads(0)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut server = Server::with_network(config, self.network.host(ip))?;
+        server.set_clock(Arc::new(self.clock.clone()));
+        server.set_address_book(AddressBook::with_key(self.network.random()));
+        server.set_seed(self.network.random());
+
+        self.nodes.push(server);
+        self.addresses.push(addr);
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, index: usize) -> &Server<SimNetwork> {
+        &self.nodes[index]
+    }
+
+    pub fn node_mut(&mut self, index: usize) -> &mut Server<SimNetwork> {
+        &mut self.nodes[index]
+    }
+
+    /// Address the node listens on.
+    pub fn addr(&self, index: usize) -> SocketAddr {
+        self.addresses[index]
+    }
+
+    /// The node `from` keeps a connection to the node `to`.
+    pub fn connect(&mut self, from: usize, to: usize) {
+        let addr = self.addresses[to];
+        self.nodes[from].add_peer(addr);
+    }
+
+    /// Splits the network, the nodes not given are together.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        let mut hosts = HashMap::new();
+        for (group, nodes) in groups.iter().enumerate() {
+            for node in nodes.iter() {
+                hosts.insert(self.addresses[*node].ip(), group + 1);
+            }
+        }
+        self.network.set_groups(hosts);
+    }
+
+    /// Reconnects the whole network.
+    pub fn heal(&mut self) {
+        self.network.set_groups(HashMap::new());
+    }
+
+    /// Simulated time since the start.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// Moves the time forward, tick by tick.
+    pub fn step(&mut self, duration: Duration) -> io::Result<()> {
+        let end = self.clock.elapsed() + duration;
+        while self.clock.elapsed() < end {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until the condition holds, at most `limit`.
+    /// Returns the simulated time at which it held.
+    pub fn run_until<F>(&mut self, limit: Duration, condition: F) -> io::Result<Option<Duration>>
+        where F: Fn(&Simulator) -> bool {
+        let end = self.clock.elapsed() + limit;
+        while !condition(self) {
+            if self.clock.elapsed() >= end {
+                return Ok(None);
+            }
+            self.tick()?;
+        }
+        Ok(Some(self.clock.elapsed()))
+    }
+
+    /// Whether every node gives the same value, such as the same tip.
+    pub fn converged<T, F>(&self, value: F) -> bool
+        where T: PartialEq, F: Fn(&Server<SimNetwork>) -> T {
+        let mut values = self.nodes.iter().map(value);
+        match values.next() {
+            Some(first) => values.all(|v| v == first),
+            None => true,
+        }
+    }
+
+    /// Number of established connections of the node.
+    pub fn established(&self, index: usize) -> usize {
+        self.nodes[index].peer_info().iter().filter(|p| p.established).count()
+    }
+
+    fn tick(&mut self) -> io::Result<()> {
+        self.clock.advance(TICK);
+        self.network.deliver();
+        for node in self.nodes.iter_mut() {
+            node.step(Some(Duration::from_secs(0)))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.nodes.clear();
+        let _ = fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three nodes in a line: 2 -> 1 -> 0.
+    fn line(seed: u64) -> Simulator {
+        let mut sim = Simulator::new(seed);
+        for _ in 0..3 {
+            sim.add_node().unwrap();
+        }
+        sim.connect(1, 0);
+        sim.connect(2, 1);
+        sim
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut sim = line(1);
+        let time = sim.run_until(Duration::from_secs(10), |sim| sim.established(1) == 2).unwrap();
+        assert!(time.is_some());
+
+        // The whoami and addr messages taught the nodes their neighbours.
+        assert!(sim.node(0).address_book().get(&sim.addr(1)).is_some());
+        assert!(sim.node(1).address_book().get(&sim.addr(2)).is_some());
+        assert!(sim.node(2).address_book().get(&sim.addr(1)).unwrap().tried);
+        assert!(sim.converged(|node| node.network_time()));
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut sim = line(1);
+        sim.network().set_latency(Duration::from_secs(1));
+        let time = sim.run_until(Duration::from_secs(30), |sim| sim.established(0) == 1).unwrap();
+        // whoami, whoami + whoamiack, whoamiack: three trips.
+        assert!(time.unwrap() >= Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let run = |seed| {
+            let mut sim = line(seed);
+            sim.network().set_loss(0.5);
+            sim.run_until(Duration::from_secs(60), |sim| sim.established(1) == 2).unwrap()
+        };
+        let time = run(7);
+        assert!(time.is_some());
+        assert_eq!(time, run(7));
+    }
+
+    #[test]
+    fn test_partition() {
+        let mut sim = line(1);
+        sim.partition(&[&[0]]);
+        sim.step(Duration::from_secs(5)).unwrap();
+        assert_eq!(sim.established(0), 0);
+        assert_eq!(sim.established(1), 1);
+
+        sim.heal();
+        let time = sim.run_until(Duration::from_secs(60), |sim| sim.established(0) == 1).unwrap();
+        assert!(time.is_some());
+    }
+
+    #[test]
+    fn test_inventory_gossip() {
+        use crate::messages::inv::{InvType, Inventory};
+
+        let mut sim = line(3);
+        sim.run_until(Duration::from_secs(10), |sim| sim.established(1) == 2).unwrap();
+
+        let block = Inventory::new(InvType::Block, [1; 32]);
+        let invalid = Inventory::new(InvType::Transaction, [2; 32]);
+        sim.node_mut(2).reject_inventory(invalid);
+        assert!(sim.node_mut(0).relay_inventory(block, None));
+        assert!(sim.node_mut(0).relay_inventory(invalid, None));
+        assert!(!sim.node_mut(0).relay_inventory(block, None));
+
+        let time = sim.run_until(Duration::from_secs(10), |sim| sim.converged(|n| n.has_inventory(&block)));
+        assert!(time.unwrap().is_some());
+        // The transaction is trickled.
+        let time = sim.run_until(Duration::from_secs(60), |sim| sim.node(1).has_inventory(&invalid));
+        assert!(time.unwrap().is_some());
+        sim.step(Duration::from_secs(60)).unwrap();
+        assert!(!sim.node(2).has_inventory(&invalid));
+    }
+
+    #[test]
+    fn test_dandelion() {
+        use crate::messages::inv::{InvType, Inventory};
+
+        let mut sim = Simulator::new(4);
+        for _ in 0..3 {
+            sim.add_node_with(ServerConfig::builder().dandelion(true)).unwrap();
+        }
+        sim.connect(1, 0);
+        sim.connect(2, 1);
+        sim.run_until(Duration::from_secs(10), |sim| sim.established(1) == 2).unwrap();
+
+        // The origin does not announce its own transaction.
+        let tx = Inventory::new(InvType::Transaction, [3; 32]);
+        assert!(sim.node_mut(2).submit_inventory(tx));
+        assert!(!sim.node(2).has_inventory(&tx));
+
+        let time = sim.run_until(Duration::from_secs(120), |sim| sim.converged(|n| n.has_inventory(&tx)));
+        assert!(time.unwrap().is_some());
+    }
+}
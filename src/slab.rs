@@ -0,0 +1,234 @@
+// Table of the connections, indexed by their poll token.
+use std::mem;
+
+use mio::Token;
+
+/// Bits of a token holding the index of the slot,
+/// the others hold the generation of the slot.
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+/// The highest bit is never set, the tokens of the waker and
+/// the signals live up there.
+const MAX_GENERATION: usize = (1 << (usize::BITS - INDEX_BITS - 1)) - 1;
+
+#[derive(Debug)]
+enum Slot<T> {
+    Occupied(T),
+    Vacant(Option<usize>),  // Next free slot.
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    generation: usize,  // Increased each time the slot is freed.
+    slot: Slot<T>,
+}
+
+/// Values stored in reusable slots.
+///
+/// A token is made of the index of the slot and of its generation,
+/// which changes whenever the slot is freed. The token of a removed
+/// value thus never gives the value that took its slot.
+///
+/// The generations start at 1, so the tokens never collide with
+/// the small ones of the listeners.
+#[derive(Debug)]
+pub struct Slab<T> {
+    entries: Vec<Entry<T>>,
+    free: Option<usize>,  // First free slot.
+    len: usize,
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Slab {
+            entries: Vec::new(),
+            free: None,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Token the next inserted value gets.
+    pub fn vacant_token(&self) -> Token {
+        match self.free {
+            Some(index) => token(index, self.entries[index].generation),
+            None => token(self.entries.len(), 1),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> Token {
+        self.len += 1;
+        match self.free {
+            Some(index) => {
+                let entry = &mut self.entries[index];
+                if let Slot::Vacant(next) = entry.slot {
+                    self.free = next;
+                }
+                entry.slot = Slot::Occupied(value);
+                token(index, entry.generation)
+            },
+            None => {
+                self.entries.push(Entry {
+                    generation: 1,
+                    slot: Slot::Occupied(value),
+                });
+                token(self.entries.len() - 1, 1)
+            },
+        }
+    }
+
+    pub fn get(&self, token: Token) -> Option<&T> {
+        let (index, generation) = split(token);
+        match self.entries.get(index) {
+            Some(Entry { generation: g, slot: Slot::Occupied(value) }) if *g == generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        let (index, generation) = split(token);
+        match self.entries.get_mut(index) {
+            Some(Entry { generation: g, slot: Slot::Occupied(value) }) if *g == generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, token: Token) -> bool {
+        self.get(token).is_some()
+    }
+
+    /// Frees the slot of the value, a stale token gives `None`.
+    pub fn remove(&mut self, token: Token) -> Option<T> {
+        if !self.contains(token) {
+            return None;
+        }
+
+        let index = split(token).0;
+        let entry = &mut self.entries[index];
+        entry.generation = if entry.generation == MAX_GENERATION { 1 } else { entry.generation + 1 };
+        let slot = mem::replace(&mut entry.slot, Slot::Vacant(self.free));
+        self.free = Some(index);
+        self.len -= 1;
+        match slot {
+            Slot::Occupied(value) => Some(value),
+            Slot::Vacant(_) => None,
+        }
+    }
+
+    /// Removes the values for which `keep` is false.
+    pub fn retain<F: FnMut(Token, &mut T) -> bool>(&mut self, mut keep: F) {
+        for token in self.tokens() {
+            if let Some(value) = self.get_mut(token) {
+                if !keep(token, value) {
+                    self.remove(token);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.retain(|_, _| false);
+    }
+
+    /// Tokens of the values, by order of their slots.
+    pub fn tokens(&self) -> Vec<Token> {
+        self.iter().map(|(token, _)| token).collect()
+    }
+
+    /// The values by order of their slots, which is the same from run to run.
+    pub fn iter(&self) -> impl Iterator<Item = (Token, &T)> {
+        self.entries.iter().enumerate().filter_map(|(index, entry)| match &entry.slot {
+            Slot::Occupied(value) => Some((token(index, entry.generation), value)),
+            Slot::Vacant(_) => None,
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Token, &mut T)> {
+        self.entries.iter_mut().enumerate().filter_map(|(index, entry)| match &mut entry.slot {
+            Slot::Occupied(value) => Some((token(index, entry.generation), value)),
+            Slot::Vacant(_) => None,
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.entries.iter_mut().filter_map(|entry| match &mut entry.slot {
+            Slot::Occupied(value) => Some(value),
+            Slot::Vacant(_) => None,
+        })
+    }
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn token(index: usize, generation: usize) -> Token {
+    Token(generation << INDEX_BITS | index)
+}
+
+/// Index and generation of a token.
+fn split(token: Token) -> (usize, usize) {
+    (token.0 & INDEX_MASK, token.0 >> INDEX_BITS)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let mut slab = Slab::new();
+        let a = slab.insert("a");
+        let b = slab.insert("b");
+        assert_eq!(slab.len(), 2);
+        assert_eq!(slab.get(a), Some(&"a"));
+
+        assert_eq!(slab.remove(a), Some("a"));
+        assert_eq!(slab.remove(a), None);
+        let expected = slab.vacant_token();
+        let c = slab.insert("c");
+        assert_eq!(c, expected);
+
+        // The slot is reused, the old token does not give the new value.
+        assert_eq!(split(c).0, split(a).0);
+        assert_ne!(c, a);
+        assert_eq!(slab.get(a), None);
+        assert_eq!(slab.get(c), Some(&"c"));
+        assert_eq!(slab.values().copied().collect::<Vec<_>>(), vec!["c", "b"]);
+
+        slab.retain(|token, _| token != b);
+        assert_eq!(slab.len(), 1);
+        slab.clear();
+        assert!(slab.is_empty());
+    }
+
+    #[test]
+    fn test_tokens() {
+        let mut slab = Slab::new();
+        let token = slab.insert(0);
+        // Far from the tokens of the listeners, the waker and the signals.
+        assert!(token.0 > 1 << 16);
+        assert!(token.0 < usize::MAX / 2);
+        assert_eq!(slab.get(Token(0)), None);
+        assert_eq!(slab.get(Token(usize::MAX)), None);
+
+        // The generation wraps around, without reaching the highest bit.
+        slab.entries[0].generation = MAX_GENERATION;
+        slab.remove(slab.tokens()[0]);
+        assert_eq!(slab.entries[0].generation, 1);
+    }
+}
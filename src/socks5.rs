@@ -0,0 +1,186 @@
+// Client side of the SOCKS5 protocol (RFC 1928), without authentication.
+use std::io;
+use std::net::SocketAddr;
+
+pub const SOCKS_VERSION: u8 = 5;
+pub const NO_AUTHENTICATION: u8 = 0;
+pub const CONNECT_COMMAND: u8 = 1;
+pub const IPV4_TYPE: u8 = 1;
+pub const DOMAIN_TYPE: u8 = 3;
+pub const IPV6_TYPE: u8 = 4;
+pub const SUCCEEDED: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Socks5State {
+    WaitingMethod,  // The greeting is sent, waiting for the chosen method.
+    WaitingReply,  // The connect request is sent, waiting for the proxy's reply.
+    Done,
+}
+
+/// Non-blocking SOCKS5 handshake asking a proxy to connect us to `target`.
+///
+/// The bytes returned by `start` and `advance` are sent to the proxy,
+/// and the bytes received from the proxy are given to `advance`.
+#[derive(Debug)]
+pub struct Socks5Handshake {
+    target: SocketAddr,
+    state: Socks5State,
+}
+
+impl Socks5Handshake {
+    pub fn new(target: SocketAddr) -> Self {
+        Socks5Handshake {
+            target,
+            state: Socks5State::WaitingMethod,
+        }
+    }
+
+    /// First message to send to the proxy.
+    pub fn start(&self) -> Vec<u8> {
+        vec![SOCKS_VERSION, 1, NO_AUTHENTICATION]
+    }
+
+    /// Reads the proxy's answers from the buffer, removing them.
+    ///
+    /// Returns the bytes to send to the proxy, empty if more data is needed
+    /// or if the handshake is done. Fails if the proxy refuses the connection.
+    pub fn advance(&mut self, buffer: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+        match self.state {
+            Socks5State::WaitingMethod => {
+                if buffer.len() < 2 {
+                    return Ok(Vec::new());
+                }
+
+                let reply: Vec<u8> = buffer.drain(..2).collect();
+                if reply[0] != SOCKS_VERSION {
+                    return Err(proxy_error("Not a SOCKS5 proxy"));
+                }
+                if reply[1] != NO_AUTHENTICATION {
+                    return Err(proxy_error("The proxy requires an authentication"));
+                }
+
+                self.state = Socks5State::WaitingReply;
+                Ok(self.connect_request())
+            },
+            Socks5State::WaitingReply => {
+                if buffer.len() < 5 {
+                    return Ok(Vec::new());
+                }
+
+                if buffer[0] != SOCKS_VERSION {
+                    return Err(proxy_error("Not a SOCKS5 proxy"));
+                }
+                if buffer[1] != SUCCEEDED {
+                    return Err(proxy_error(reply_message(buffer[1])));
+                }
+
+                // The reply ends with the address bound by the proxy.
+                let address_length = match buffer[3] {
+                    IPV4_TYPE => 4,
+                    IPV6_TYPE => 16,
+                    DOMAIN_TYPE => 1 + buffer[4] as usize,
+                    _ => return Err(proxy_error("Unknown address type")),
+                };
+                let length = 4 + address_length + 2;
+                if buffer.len() < length {
+                    return Ok(Vec::new());
+                }
+
+                buffer.drain(..length);
+                self.state = Socks5State::Done;
+                Ok(Vec::new())
+            },
+            Socks5State::Done => Ok(Vec::new()),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == Socks5State::Done
+    }
+
+    /// Asks the proxy to connect to the target.
+    fn connect_request(&self) -> Vec<u8> {
+        let mut request = vec![SOCKS_VERSION, CONNECT_COMMAND, 0];
+        match self.target {
+            SocketAddr::V4(addr) => {
+                request.push(IPV4_TYPE);
+                request.extend(&addr.ip().octets());
+            },
+            SocketAddr::V6(addr) => {
+                request.push(IPV6_TYPE);
+                request.extend(&addr.ip().octets());
+            },
+        }
+        request.extend(&self.target.port().to_be_bytes());
+        request
+    }
+}
+
+fn proxy_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, format!("SOCKS5: {}", message))
+}
+
+/// Meaning of the reply field.
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        1 => "General failure",
+        2 => "Connection not allowed by ruleset",
+        3 => "Network unreachable",
+        4 => "Host unreachable",
+        5 => "Connection refused",
+        6 => "TTL expired",
+        7 => "Command not supported",
+        8 => "Address type not supported",
+        _ => "Unknown error",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let mut handshake = Socks5Handshake::new("1.2.3.4:8000".parse().unwrap());
+        assert_eq!(handshake.start(), vec![5, 1, 0]);
+
+        let mut buffer = vec![5];
+        assert!(handshake.advance(&mut buffer).unwrap().is_empty());
+        buffer.push(0);
+        assert_eq!(handshake.advance(&mut buffer).unwrap(),
+            vec![5, 1, 0, 1, 1, 2, 3, 4, 0x1f, 0x40]);
+        assert!(buffer.is_empty());
+
+        // The reply can come in pieces, and be followed by the node's data.
+        let mut buffer = vec![5, 0, 0, 1, 127, 0];
+        assert!(handshake.advance(&mut buffer).unwrap().is_empty());
+        assert!(!handshake.is_done());
+        buffer.extend(&[0, 1, 0x1f, 0x40, 42]);
+        handshake.advance(&mut buffer).unwrap();
+        assert!(handshake.is_done());
+        assert_eq!(buffer, vec![42]);
+    }
+
+    #[test]
+    fn test_ipv6_target() {
+        let mut handshake = Socks5Handshake::new("[::1]:1".parse().unwrap());
+        let request = handshake.advance(&mut vec![5, 0]).unwrap();
+        assert_eq!(request[3], IPV6_TYPE);
+        assert_eq!(request.len(), 4 + 16 + 2);
+
+        let mut buffer = vec![5, 0, 0, DOMAIN_TYPE, 3, b'a', b'b', b'c', 0, 1];
+        handshake.advance(&mut buffer).unwrap();
+        assert!(handshake.is_done());
+    }
+
+    #[test]
+    fn test_refused() {
+        let mut handshake = Socks5Handshake::new("1.2.3.4:8000".parse().unwrap());
+        assert!(handshake.advance(&mut vec![5, 0xff]).is_err());
+
+        let mut handshake = Socks5Handshake::new("1.2.3.4:8000".parse().unwrap());
+        handshake.advance(&mut vec![5, 0]).unwrap();
+        assert!(handshake.advance(&mut vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+}
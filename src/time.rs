@@ -0,0 +1,282 @@
+// Time related helpers.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::netgroup::netgroup;
+
+/// Minimum number of peers needed before we trust their clocks.
+pub const MIN_TIME_SAMPLES: usize = 3;
+/// Above this offset (in secs), our local clock is considered wrong.
+pub const MAX_CLOCK_DRIFT: u64 = 5 * 60;
+/// The network can not move our clock further than this (in secs).
+pub const MAX_TIME_ADJUSTMENT: u64 = 70 * 60;
+/// A block can not be timestamped further than this in the future (in secs).
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// Current local time, in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Source of the current time, so that tests can control it.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Monotonic time, used for the timeouts.
+    fn instant(&self) -> Instant;
+
+    /// Seconds since the Unix epoch, used for the timestamps.
+    fn unix(&self) -> u64;
+}
+
+/// The clock of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix(&self) -> u64 {
+        now()
+    }
+}
+
+/// A clock only moving when told to.
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start: (Instant, u64),
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    /// Starts at the given Unix time.
+    pub fn new(unix: u64) -> Self {
+        MockClock {
+            start: (Instant::now(), unix),
+            elapsed: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// Time elapsed since the creation of the clock.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for MockClock {
+    fn instant(&self) -> Instant {
+        self.start.0 + self.elapsed()
+    }
+
+    fn unix(&self) -> u64 {
+        self.start.1 + self.elapsed().as_secs()
+    }
+}
+
+/// Network-adjusted time.
+///
+/// Each peer gives us its time in the whoami message. We keep the offset
+/// between its clock and ours, and correct our time by the median offset.
+/// Each network group gives a single sample.
+#[derive(Debug, Default)]
+pub struct NetworkTime {
+    offsets: HashMap<Vec<u8>, (SocketAddr, i64)>,  // netgroup -> last peer and (peer time - local time)
+    warned: bool,
+}
+
+impl NetworkTime {
+    pub fn new() -> Self {
+        NetworkTime {
+            offsets: HashMap::new(),
+            warned: false,
+        }
+    }
+
+    /// Save the time given by a peer, compared to our local time.
+    pub fn add_sample(&mut self, peer: SocketAddr, peer_time: u64, local_time: u64) {
+        if peer_time == 0 {
+            return;  // The peer did not give us its time.
+        }
+
+        // The peer may give any timestamp, far beyond what an i64 offset holds.
+        let offset = (i128::from(peer_time) - i128::from(local_time))
+            .clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+        self.offsets.insert(netgroup(&peer.ip()), (peer, offset));
+        self.check_drift();
+    }
+
+    /// Remove the sample of a disconnected peer, if it still holds
+    /// the sample of its group.
+    pub fn remove_sample(&mut self, peer: &SocketAddr) {
+        let group = netgroup(&peer.ip());
+        if self.offsets.get(&group).map(|(sampler, _)| sampler) == Some(peer) {
+            self.offsets.remove(&group);
+        }
+    }
+
+    /// Median offset of the peers' clocks, in secs.
+    /// Is 0 while we don't have enough samples.
+    pub fn median_offset(&self) -> i64 {
+        if self.offsets.len() < MIN_TIME_SAMPLES {
+            return 0;
+        }
+
+        let mut offsets: Vec<i64> = self.offsets.values().map(|&(_, offset)| offset).collect();
+        offsets.sort_unstable();
+
+        let middle = offsets.len() / 2;
+        if offsets.len().is_multiple_of(2) {
+            ((i128::from(offsets[middle - 1]) + i128::from(offsets[middle])) / 2) as i64
+        } else {
+            offsets[middle]
+        }
+    }
+
+    /// Correction applied to our local time, in secs.
+    /// An offset too big to be trusted is not applied.
+    pub fn offset(&self) -> i64 {
+        let median = self.median_offset();
+        if median.unsigned_abs() > MAX_TIME_ADJUSTMENT {
+            0
+        } else {
+            median
+        }
+    }
+
+    /// Network-adjusted time, in seconds since the Unix epoch.
+    pub fn adjusted(&self, local_time: u64) -> u64 {
+        (local_time as i64 + self.offset()).max(0) as u64
+    }
+
+    /// Whether a block timestamp is acceptable, that is
+    /// not too far in the future of the network-adjusted time.
+    pub fn is_valid_block_time(&self, timestamp: u64, local_time: u64) -> bool {
+        timestamp <= self.adjusted(local_time) + MAX_FUTURE_BLOCK_TIME
+    }
+
+    /// Warn when our clock is too far from the peers' ones.
+    /// The warning is only given once, until the clocks agree again.
+    fn check_drift(&mut self) {
+        let median = self.median_offset();
+        if median.unsigned_abs() <= MAX_CLOCK_DRIFT {
+            self.warned = false;
+            return;
+        }
+
+        if !self.warned {
+            println!("**************************************************");
+            println!("WARNING: your clock is {} secs off the network time!", median);
+            println!("Please check that the date and time of your computer are correct.");
+            if median.unsigned_abs() > MAX_TIME_ADJUSTMENT {
+                println!("The offset is too big to be corrected, the local time is used.");
+            }
+            println!("**************************************************");
+            self.warned = true;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A peer in a network group of its own.
+    fn peer(group: u8) -> SocketAddr {
+        SocketAddr::new([1, group, 0, 1].into(), 8333)
+    }
+
+    #[test]
+    fn test_median_offset() {
+        let mut time = NetworkTime::new();
+        time.add_sample(peer(1), 1010, 1000);
+        time.add_sample(peer(2), 1020, 1000);
+        assert_eq!(time.median_offset(), 0);  // Not enough samples
+
+        time.add_sample(peer(3), 900, 1000);
+        assert_eq!(time.median_offset(), 10);
+        assert_eq!(time.adjusted(2000), 2010);
+
+        time.add_sample(peer(4), 1030, 1000);
+        assert_eq!(time.median_offset(), 15);
+
+        time.remove_sample(&peer(4));
+        assert_eq!(time.median_offset(), 10);
+    }
+
+    #[test]
+    fn test_ignore_empty_timestamps() {
+        let mut time = NetworkTime::new();
+        for group in 0..MIN_TIME_SAMPLES as u8 {
+            time.add_sample(peer(group), 0, 1000);
+        }
+        assert_eq!(time.median_offset(), 0);
+    }
+
+    #[test]
+    fn test_big_offset_not_applied() {
+        let mut time = NetworkTime::new();
+        for group in 0..MIN_TIME_SAMPLES as u8 {
+            time.add_sample(peer(group), 1000 + MAX_TIME_ADJUSTMENT + 1, 1000);
+        }
+        assert!(time.warned);
+        assert_eq!(time.offset(), 0);
+        assert_eq!(time.adjusted(1000), 1000);
+    }
+
+    #[test]
+    fn test_block_time() {
+        let mut time = NetworkTime::new();
+        for group in 0..MIN_TIME_SAMPLES as u8 {
+            time.add_sample(peer(group), 1600, 1000);
+        }
+        assert!(time.is_valid_block_time(1600 + MAX_FUTURE_BLOCK_TIME, 1000));
+        assert!(!time.is_valid_block_time(1601 + MAX_FUTURE_BLOCK_TIME, 1000));
+    }
+
+    #[test]
+    fn test_extreme_timestamps() {
+        let mut time = NetworkTime::new();
+        time.add_sample(peer(0), 1 << 63, 1000);
+        time.add_sample(peer(1), u64::MAX, 1000);
+        time.add_sample(peer(2), 1, u64::MAX);
+        time.add_sample(peer(3), u64::MAX, u64::MAX);
+        assert!(time.median_offset() > MAX_TIME_ADJUSTMENT as i64);
+        assert_eq!(time.offset(), 0);  // Too big to be applied.
+        assert_eq!(time.adjusted(1000), 1000);
+    }
+
+    #[test]
+    fn test_one_sample_per_group() {
+        let mut time = NetworkTime::new();
+        for port in 0..MIN_TIME_SAMPLES as u16 {
+            let sybil = SocketAddr::new("9.9.0.1".parse().unwrap(), port);
+            time.add_sample(sybil, 1000 + MAX_CLOCK_DRIFT, 1000);
+        }
+        assert_eq!(time.median_offset(), 0);  // Not enough samples
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(1_000);
+        let shared = clock.clone();
+        let start = clock.instant();
+
+        shared.advance(Duration::from_millis(1_500));
+        assert_eq!(clock.instant().duration_since(start), Duration::from_millis(1_500));
+        assert_eq!(clock.unix(), 1_001);
+        assert_eq!(clock.elapsed(), Duration::from_millis(1_500));
+    }
+}
@@ -0,0 +1,56 @@
+// What nodes talk through: TCP, or anything else behaving like it.
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream};
+
+/// Non-blocking byte stream between us and a node.
+///
+/// Reads and writes return `WouldBlock` instead of waiting,
+/// and the stream is registered in the poll of the server.
+pub trait Stream: Read + Write + Source + fmt::Debug + Send {}
+
+impl Stream for TcpStream {}
+
+/// How a server listens for nodes and connects to them.
+pub trait Network: fmt::Debug + Send {
+    type Stream: Stream;
+    type Listener: Source + fmt::Debug + Send;
+
+    fn bind(&self, addr: SocketAddr) -> io::Result<Self::Listener>;
+
+    /// Accepts a pending connection, `WouldBlock` if there is none.
+    fn accept(&self, listener: &Self::Listener) -> io::Result<(Self::Stream, SocketAddr)>;
+
+    /// Starts connecting to a node. The connection may not be established yet.
+    fn connect(&self, addr: SocketAddr) -> io::Result<Self::Stream>;
+
+    fn local_addr(&self, listener: &Self::Listener) -> io::Result<SocketAddr>;
+}
+
+/// The real network.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpNetwork;
+
+impl Network for TcpNetwork {
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        TcpListener::bind(addr)
+    }
+
+    fn accept(&self, listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+        listener.accept()
+    }
+
+    fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(addr)
+    }
+
+    fn local_addr(&self, listener: &TcpListener) -> io::Result<SocketAddr> {
+        listener.local_addr()
+    }
+}
@@ -0,0 +1,224 @@
+// Pool of threads processing the messages of the nodes.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use mio::{Token, Waker};
+
+/// Jobs waiting to be processed.
+#[derive(Debug)]
+struct Queue<J> {
+    jobs: HashMap<Token, VecDeque<J>>,  // Jobs of each node, in order.
+    ready: VecDeque<Token>,  // Nodes with jobs and no worker, served in turn.
+    busy: HashSet<Token>,  // Nodes a worker is processing a job of.
+    stopping: bool,
+}
+
+#[derive(Debug)]
+struct Shared<J> {
+    queue: Mutex<Queue<J>>,
+    condvar: Condvar,
+}
+
+/// Processes jobs on several threads, for the network thread.
+///
+/// The jobs of a node are processed one at a time and their results
+/// come back in the order they were submitted. The nodes with waiting
+/// jobs are served in turn, one job each, so a node flooding us or sending
+/// costly messages only slows itself down.
+///
+/// The waker is woken up whenever a result is ready.
+#[derive(Debug)]
+pub struct WorkerPool<J, R> {
+    shared: Arc<Shared<J>>,
+    results: Receiver<(Token, R)>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<J: Send + 'static, R: Send + 'static> WorkerPool<J, R> {
+    pub fn new(threads: usize, waker: Arc<Waker>, process: fn(J) -> R) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: HashMap::new(),
+                ready: VecDeque::new(),
+                busy: HashSet::new(),
+                stopping: false,
+            }),
+            condvar: Condvar::new(),
+        });
+        let (sender, results) = mpsc::channel();
+
+        let threads = (0..threads)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                let sender = sender.clone();
+                let waker = Arc::clone(&waker);
+                thread::spawn(move || work(shared, sender, waker, process))
+            })
+            .collect();
+
+        WorkerPool { shared, results, threads }
+    }
+
+    /// Queues a job of the node.
+    pub fn submit(&self, token: Token, job: J) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let jobs = queue.jobs.entry(token).or_default();
+        jobs.push_back(job);
+        if jobs.len() == 1 && !queue.busy.contains(&token) {
+            queue.ready.push_back(token);
+            self.shared.condvar.notify_one();
+        }
+    }
+
+    /// Drops the waiting jobs of a node, once disconnected.
+    /// The result of a job being processed may still come back.
+    pub fn remove(&self, token: Token) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.jobs.remove(&token);
+        queue.ready.retain(|t| *t != token);
+    }
+
+    /// Number of jobs of the node not processed yet.
+    pub fn pending(&self, token: Token) -> usize {
+        let queue = self.shared.queue.lock().unwrap();
+        queue.jobs.get(&token).map(VecDeque::len).unwrap_or(0)
+            + queue.busy.contains(&token) as usize
+    }
+
+    /// A result, if one is ready.
+    pub fn try_recv(&self) -> Option<(Token, R)> {
+        self.results.try_recv().ok()
+    }
+}
+
+impl<J, R> Drop for WorkerPool<J, R> {
+    /// Lets the workers finish their current job and stops them.
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().stopping = true;
+        self.shared.condvar.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Loop of a worker thread.
+fn work<J, R>(shared: Arc<Shared<J>>, sender: Sender<(Token, R)>, waker: Arc<Waker>,
+        process: fn(J) -> R) {
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        if queue.stopping {
+            return;
+        }
+
+        let token = match queue.ready.pop_front() {
+            Some(token) => token,
+            None => {
+                queue = shared.condvar.wait(queue).unwrap();
+                continue;
+            }
+        };
+        let job = match queue.jobs.get_mut(&token).and_then(VecDeque::pop_front) {
+            Some(job) => job,
+            None => continue,
+        };
+        queue.busy.insert(token);
+        drop(queue);
+
+        let result = process(job);
+        // The result is sent before the next job of the node can start, to keep the order.
+        let stopped = sender.send((token, result)).is_err();
+        if let Err(err) = waker.wake() {
+            println!("Could not wake the server up: {}", err);
+        }
+
+        queue = shared.queue.lock().unwrap();
+        queue.busy.remove(&token);
+        if stopped {
+            return;
+        }
+        match queue.jobs.get(&token).map(VecDeque::len) {
+            Some(0) => {
+                queue.jobs.remove(&token);
+            },
+            Some(_) => {
+                // Back at the end of the line, behind the other nodes.
+                queue.ready.push_back(token);
+                shared.condvar.notify_one();
+            },
+            None => (),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::Poll;
+    use std::time::{Duration, Instant};
+
+    /// Sleeps for the given milliseconds.
+    fn slow(job: (u64, usize)) -> usize {
+        thread::sleep(Duration::from_millis(job.0));
+        job.1
+    }
+
+    fn pool(threads: usize) -> (WorkerPool<(u64, usize), usize>, Poll) {
+        let poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), Token(usize::MAX)).unwrap());
+        (WorkerPool::new(threads, waker, slow), poll)
+    }
+
+    fn wait_results(pool: &WorkerPool<(u64, usize), usize>, count: usize) -> Vec<(Token, usize)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut results = Vec::new();
+        while results.len() < count && Instant::now() < deadline {
+            match pool.try_recv() {
+                Some(result) => results.push(result),
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn test_order() {
+        let (pool, _poll) = pool(4);
+        for i in 0..50 {
+            pool.submit(Token(i % 3), ((i % 2) as u64, i));
+        }
+
+        let results = wait_results(&pool, 50);
+        assert_eq!(results.len(), 50);
+        for node in 0..3 {
+            let jobs: Vec<usize> = results.iter()
+                .filter(|(token, _)| *token == Token(node))
+                .map(|(_, job)| *job)
+                .collect();
+            let mut sorted = jobs.clone();
+            sorted.sort();
+            assert_eq!(jobs, sorted);
+        }
+    }
+
+    #[test]
+    fn test_fairness() {
+        let (pool, _poll) = pool(1);
+        // A node floods us with slow jobs, another sends one quick job.
+        for i in 0..20 {
+            pool.submit(Token(1), (20, i));
+        }
+        pool.submit(Token(2), (0, 100));
+
+        let results = wait_results(&pool, 3);
+        assert!(results.contains(&(Token(2), 100)));
+        assert!(pool.pending(Token(1)) > 10);
+
+        // Only the job being processed is left.
+        pool.remove(Token(1));
+        assert!(pool.pending(Token(1)) <= 1);
+    }
+}